@@ -0,0 +1,123 @@
+use crate::audit::{AuditEvent, AuditLog, AuditQuery};
+
+use actix_web::web;
+use async_trait::async_trait;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Arc;
+
+// One JSON `AuditEvent` per line, appended in the order they happen.
+// File IO runs on the blocking thread pool, queries read the whole file.
+pub struct FileAuditLog {
+    path: String,
+    file: Arc<File>,
+}
+
+impl FileAuditLog {
+    pub fn new(path: String) -> Result<FileAuditLog, Box<dyn std::error::Error>> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileAuditLog {
+            path,
+            file: Arc::new(file),
+        })
+    }
+}
+
+fn read_events(path: &str, query: &AuditQuery) -> std::io::Result<Vec<AuditEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        // A line cut short by a crash is skipped
+        let event: AuditEvent = match serde_json::from_str(&line?) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        if query.matches(&event) {
+            events.push(event);
+        }
+    }
+    let skip = events.len().saturating_sub(query.limit());
+    Ok(events.into_iter().skip(skip).rev().collect())
+}
+
+#[async_trait]
+impl AuditLog for FileAuditLog {
+    async fn record(&mut self, event: AuditEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let line = serde_json::to_string(&event)? + "\n";
+        let file = self.file.clone();
+        // Appends of a single write do not interleave
+        web::block(move || (&*file).write_all(line.as_bytes())).await??;
+        Ok(())
+    }
+
+    async fn query(
+        &mut self,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEvent>, Box<dyn std::error::Error>> {
+        let path = self.path.clone();
+        let query = query.clone();
+        Ok(web::block(move || read_events(&path, &query)).await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{AuditAction, AuditOutcome};
+    use std::env;
+    use std::fs;
+
+    fn event(timestamp: u64, username: &str, action: AuditAction) -> AuditEvent {
+        AuditEvent {
+            timestamp,
+            username: Some(username.to_string()),
+            ip: Some("127.0.0.1".to_string()),
+            user_agent: None,
+            action,
+            outcome: AuditOutcome::Success,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_record_and_query() {
+        let path = env::temp_dir()
+            .join(format!("lynx-audit-{}.jsonl", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut log = FileAuditLog::new(path.clone()).unwrap();
+        log.record(event(100, "alice", AuditAction::Register))
+            .await
+            .unwrap();
+        log.record(event(200, "bob", AuditAction::Login))
+            .await
+            .unwrap();
+        log.record(event(300, "alice", AuditAction::StartInstance))
+            .await
+            .unwrap();
+
+        // Reopening appends
+        let mut log = FileAuditLog::new(path.clone()).unwrap();
+        log.record(event(400, "alice", AuditAction::Logout))
+            .await
+            .unwrap();
+
+        let all = log.query(&AuditQuery::default()).await.unwrap();
+        let timestamps: Vec<u64> = all.iter().map(|event| event.timestamp).collect();
+        assert_eq!(timestamps, vec![400, 300, 200, 100]);
+
+        let query = AuditQuery {
+            username: Some("alice".to_string()),
+            from: Some(150),
+            to: None,
+            limit: Some(1),
+        };
+        assert_eq!(
+            log.query(&query).await.unwrap(),
+            vec![event(400, "alice", AuditAction::Logout)]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+pub mod file_audit_log;
+pub mod no_audit_log;
+pub mod redis_audit_log;
+
+use crate::auth_manager::token;
+use crate::client_ip::client_ip;
+
+use actix_web::http::header::USER_AGENT;
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Register,
+    Login,
+    TwoFactorLogin,
+    OidcLogin,
+    CreateGuest,
+    UpgradeGuest,
+    Logout,
+    LogoutAll,
+    ChangePassword,
+    ResetPassword,
+    DeleteAccount,
+    EnableTwoFactor,
+    DisableTwoFactor,
+    CreateApiKey,
+    RevokeApiKey,
+    StartInstance,
+    StopInstance,
+    // The username of these is the affected user, not the admin
+    AdminStopInstance,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEvent {
+    // Unix timestamp
+    pub timestamp: u64,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub action: AuditAction,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditEvent {
+    pub fn new(
+        request: &HttpRequest,
+        action: AuditAction,
+        outcome: AuditOutcome,
+        username: Option<&str>,
+    ) -> AuditEvent {
+        AuditEvent {
+            timestamp: token::now_secs(),
+            username: username.map(str::to_owned),
+            ip: client_ip(request),
+            user_agent: request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned),
+            action,
+            outcome,
+        }
+    }
+
+    pub fn success(request: &HttpRequest, action: AuditAction, username: &str) -> AuditEvent {
+        AuditEvent::new(request, action, AuditOutcome::Success, Some(username))
+    }
+
+    pub fn failure(
+        request: &HttpRequest,
+        action: AuditAction,
+        username: Option<&str>,
+    ) -> AuditEvent {
+        AuditEvent::new(request, action, AuditOutcome::Failure, username)
+    }
+}
+
+// Time range bounds are inclusive unix timestamps
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuditQuery {
+    pub username: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        if let Some(username) = &self.username {
+            if event.username.as_ref() != Some(username) {
+                return false;
+            }
+        }
+        self.from.is_none_or(|from| event.timestamp >= from)
+            && self.to.is_none_or(|to| event.timestamp <= to)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .clamp(1, MAX_QUERY_LIMIT)
+    }
+}
+
+#[async_trait]
+pub trait AuditLog {
+    async fn record(&mut self, event: AuditEvent) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Newest events first
+    async fn query(
+        &mut self,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEvent>, Box<dyn std::error::Error>>;
+
+    // A broken audit log must not break logins, so errors are only logged
+    async fn log(&mut self, event: AuditEvent) {
+        let action = event.action;
+        if let Err(e) = self.record(event).await {
+            error!("Cannot record audit event {:?}: {}", action, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_event() {
+        let request = TestRequest::default()
+            .peer_addr("203.0.113.7:1234".parse().unwrap())
+            .insert_header((USER_AGENT, "curl/8.0"))
+            .to_http_request();
+        let event = AuditEvent::failure(&request, AuditAction::Login, Some("alice"));
+        assert_eq!(event.ip.as_deref(), Some("203.0.113.7"));
+        assert_eq!(event.user_agent.as_deref(), Some("curl/8.0"));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["action"], "login");
+        assert_eq!(json["outcome"], "failure");
+    }
+
+    #[test]
+    fn test_query() {
+        let request = TestRequest::default().to_http_request();
+        let mut event = AuditEvent::success(&request, AuditAction::Login, "alice");
+        event.timestamp = 100;
+
+        assert!(AuditQuery::default().matches(&event));
+        let query = AuditQuery {
+            username: Some("alice".to_string()),
+            from: Some(100),
+            to: Some(100),
+            limit: Some(5000),
+        };
+        assert!(query.matches(&event));
+        assert_eq!(query.limit(), MAX_QUERY_LIMIT);
+        let query = AuditQuery {
+            limit: Some(0),
+            ..AuditQuery::default()
+        };
+        assert_eq!(query.limit(), 1);
+
+        let query = AuditQuery {
+            username: Some("bob".to_string()),
+            ..AuditQuery::default()
+        };
+        assert!(!query.matches(&event));
+        let query = AuditQuery {
+            from: Some(101),
+            ..AuditQuery::default()
+        };
+        assert!(!query.matches(&event));
+    }
+}
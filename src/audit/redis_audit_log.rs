@@ -0,0 +1,163 @@
+use crate::audit::{AuditEvent, AuditLog, AuditQuery};
+
+use async_trait::async_trait;
+use redis::aio::Connection;
+use redis::RedisResult;
+
+// Events are appended to a capped stream, their ID being the time they
+// were recorded at in milliseconds:
+// lynx:audit - [<ms>-<seq> {event: <JSON AuditEvent>}, ...]
+const STREAM: &str = "lynx:audit";
+const MAX_EVENTS: usize = 1_000_000;
+const PAGE_SIZE: usize = 500;
+
+pub struct RedisAuditLog {
+    con: Connection,
+}
+
+impl RedisAuditLog {
+    pub async fn new(url: String) -> RedisResult<RedisAuditLog> {
+        let client = redis::Client::open(url)?;
+        let con = client.get_async_connection().await?;
+
+        Ok(RedisAuditLog { con })
+    }
+}
+
+#[async_trait]
+impl AuditLog for RedisAuditLog {
+    async fn record(&mut self, event: AuditEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let json = serde_json::to_string(&event)?;
+        let _: String = redis::cmd("XADD")
+            .arg(STREAM)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(MAX_EVENTS)
+            .arg("*")
+            .arg("event")
+            .arg(json)
+            .query_async(&mut self.con)
+            .await?;
+        Ok(())
+    }
+
+    // Pages backwards through the time range, as the username can only be filtered here
+    async fn query(
+        &mut self,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEvent>, Box<dyn std::error::Error>> {
+        let start = match query.from {
+            Some(from) => from.saturating_mul(1000).to_string(),
+            None => "-".to_string(),
+        };
+        let mut end = match query.to {
+            Some(to) => to.saturating_mul(1000).saturating_add(999).to_string(),
+            None => "+".to_string(),
+        };
+        let limit = query.limit();
+
+        let mut events = Vec::new();
+        loop {
+            let page: Vec<(String, Vec<String>)> = redis::cmd("XREVRANGE")
+                .arg(STREAM)
+                .arg(&end)
+                .arg(&start)
+                .arg("COUNT")
+                .arg(PAGE_SIZE)
+                .query_async(&mut self.con)
+                .await?;
+            for (_, fields) in &page {
+                // [field, value, ...]
+                let json = match fields.iter().position(|field| field == "event") {
+                    Some(i) => fields.get(i + 1),
+                    None => None,
+                };
+                let event: AuditEvent = match json.map(|json| serde_json::from_str(json)) {
+                    Some(Ok(event)) => event,
+                    _ => continue,
+                };
+                if query.matches(&event) {
+                    events.push(event);
+                    if events.len() == limit {
+                        return Ok(events);
+                    }
+                }
+            }
+            match page.last() {
+                Some((id, _)) if page.len() == PAGE_SIZE => end = "(".to_owned() + id,
+                _ => return Ok(events),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::env;
+
+    use super::*;
+    use crate::audit::{AuditAction, AuditOutcome};
+    use crate::auth_manager::token;
+
+    async fn get_log() -> RedisAuditLog {
+        let password = match env::var("REDIS_PASSWORD") {
+            Ok(v) => v,
+            Err(_) => panic!("$REDIS_PASSWORD is not set!"),
+        };
+
+        let url = "redis://default:".to_string() + &password + "@127.0.0.1:6379";
+        RedisAuditLog::new(url).await.unwrap()
+    }
+
+    fn event(username: &str, action: AuditAction) -> AuditEvent {
+        AuditEvent {
+            timestamp: token::now_secs(),
+            username: Some(username.to_string()),
+            ip: Some("127.0.0.1".to_string()),
+            user_agent: None,
+            action,
+            outcome: AuditOutcome::Success,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    #[serial]
+    async fn test_record_and_query() {
+        let mut log = get_log().await;
+        // Unique per run, as the stream is never cleared
+        let username = format!("lynx-test-{}", token::generate_id());
+        let from = token::now_secs();
+        log.record(event(&username, AuditAction::Register))
+            .await
+            .unwrap();
+        log.record(event("lynx-test-bob", AuditAction::Login))
+            .await
+            .unwrap();
+        log.record(event(&username, AuditAction::Logout))
+            .await
+            .unwrap();
+
+        let query = AuditQuery {
+            username: Some(username.clone()),
+            from: Some(from),
+            to: None,
+            limit: None,
+        };
+        let actions: Vec<AuditAction> = log
+            .query(&query)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.action)
+            .collect();
+        assert_eq!(actions, vec![AuditAction::Logout, AuditAction::Register]);
+
+        let query = AuditQuery {
+            limit: Some(1),
+            ..query
+        };
+        assert_eq!(log.query(&query).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::auth_manager::token;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+// API keys look like `lynx_<id>_<secret>`. The id is stored in plain to find
+// the key, the secret only as SHA-256 hash. Unlike passwords, secrets are
+// random 128 bit values, so a slow hash would only slow down every request.
+
+pub const MAX_API_KEYS: usize = 20;
+const MAX_NAME_LENGTH: usize = 64;
+const PREFIX: &str = "lynx_";
+
+// What a request authenticated with an API key may do. Everything else,
+// including admin routes and account management, requires a session.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ApiKeyScope {
+    #[serde(rename = "instance:start")]
+    InstanceStart,
+    #[serde(rename = "instance:stop")]
+    InstanceStop,
+    #[serde(rename = "proxy")]
+    Proxy,
+}
+
+impl fmt::Display for ApiKeyScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ApiKeyScope::InstanceStart => "instance:start",
+            ApiKeyScope::InstanceStop => "instance:stop",
+            ApiKeyScope::Proxy => "proxy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ApiKeyScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ApiKeyScope, String> {
+        match s {
+            "instance:start" => Ok(ApiKeyScope::InstanceStart),
+            "instance:stop" => Ok(ApiKeyScope::InstanceStop),
+            "proxy" => Ok(ApiKeyScope::Proxy),
+            _ => Err("Unknown scope: ".to_owned() + s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    // Unix timestamp
+    pub created_at: u64,
+}
+
+// Returns the full key, shown to the user once, and the hash of its secret
+pub fn generate(id: &str) -> (String, String) {
+    let secret = token::generate_id();
+    (PREFIX.to_owned() + id + "_" + &secret, hash_secret(&secret))
+}
+
+// Returns (id, secret)
+pub fn parse(key: &str) -> Option<(&str, &str)> {
+    let (id, secret) = key.strip_prefix(PREFIX)?.split_once('_')?;
+    if id.is_empty() || secret.is_empty() {
+        return None;
+    }
+    Some((id, secret))
+}
+
+pub fn verify_secret(secret: &str, hash: &str) -> bool {
+    bool::from(hash_secret(secret).as_bytes().ct_eq(hash.as_bytes()))
+}
+
+pub fn validate_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if name.trim().is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!("Key name has to be 1 to {} characters", MAX_NAME_LENGTH).into());
+    }
+    Ok(())
+}
+
+fn hash_secret(secret: &str) -> String {
+    let hash = hmac_sha256::Hash::hash(secret.as_bytes());
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_and_verify() {
+        let id = token::generate_id();
+        let (key, hash) = generate(&id);
+        let (parsed_id, secret) = parse(&key).unwrap();
+        assert_eq!(parsed_id, id);
+        assert!(verify_secret(secret, &hash));
+        assert!(!verify_secret("guess", &hash));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse("garbage"), None);
+        assert_eq!(parse("lynx_"), None);
+        assert_eq!(parse("lynx_id_"), None);
+        assert_eq!(parse("lynx__secret"), None);
+    }
+
+    #[test]
+    fn test_scope_round_trip() {
+        for scope in [
+            ApiKeyScope::InstanceStart,
+            ApiKeyScope::InstanceStop,
+            ApiKeyScope::Proxy,
+        ] {
+            assert_eq!(ApiKeyScope::from_str(&scope.to_string()), Ok(scope));
+            let json = serde_json::to_string(&scope).unwrap();
+            assert_eq!(json, format!("\"{}\"", scope));
+        }
+        assert!(ApiKeyScope::from_str("admin").is_err());
+    }
+}
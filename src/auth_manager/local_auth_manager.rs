@@ -0,0 +1,1386 @@
+use crate::auth_manager::api_key::{self, ApiKeyInfo, ApiKeyScope};
+use crate::auth_manager::login_throttle::LoginAttempts;
+use crate::auth_manager::password::{self, Verification};
+use crate::auth_manager::role::Role;
+use crate::auth_manager::token::{self, TokenClaims, TokenConfig};
+use crate::auth_manager::two_factor::{self, TotpEnrollment};
+use crate::auth_manager::{
+    login_throttle, AuthManager, LoginOutcome, UserInfo, GUEST_PREFIX, INVALID_CREDENTIALS,
+    PASSWORD_RESET_TTL,
+};
+
+use async_trait::async_trait;
+use jwt_simple::prelude::{HS256Key, JWTClaims};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use tracing::info;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct LocalUser {
+    password: String, // argon2id PHC string
+    key: Vec<u8>,
+    // jti of revoked tokens -> until when to keep them, as unix timestamp
+    #[serde(default)]
+    revoked: HashMap<String, u64>,
+    #[serde(default = "default_roles")]
+    roles: Vec<Role>,
+    #[serde(default)]
+    reset: Option<PasswordReset>,
+    #[serde(default)]
+    totp: Option<Totp>,
+    #[serde(default)]
+    pending_totp: Option<PendingTotp>,
+    // Set for users created by an OIDC login
+    #[serde(default)]
+    oidc_subject: Option<String>,
+    #[serde(default)]
+    api_keys: Vec<StoredApiKey>,
+    // Unix timestamp after which a guest is deleted, `None` for regular users
+    #[serde(default)]
+    guest_expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StoredApiKey {
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+    hash: String, // SHA-256 of the secret
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PasswordReset {
+    hash: String, // argon2id PHC string of the reset token
+    expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Totp {
+    secret: String,
+    // Time step of the last accepted code
+    last_step: u64,
+    recovery_codes: Vec<String>, // SHA-256 hashes
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PendingTotp {
+    secret: String,
+    expires_at: u64,
+}
+
+fn default_roles() -> Vec<Role> {
+    vec![Role::Player]
+}
+
+impl LocalUser {
+    fn is_revoked(&self, claims: &JWTClaims<TokenClaims>) -> bool {
+        self.revoked.contains_key(claims.jwt_id.as_ref().unwrap())
+    }
+
+    fn revoke(&mut self, claims: &JWTClaims<TokenClaims>, config: &TokenConfig) {
+        let now = token::now_secs();
+        self.revoked.retain(|_, keep_until| *keep_until > now);
+        self.revoked.insert(
+            claims.jwt_id.clone().unwrap(),
+            now + token::denylist_ttl(claims, config),
+        );
+    }
+
+    // Expired guests are treated as gone before `remove_expired_guests` runs
+    fn is_expired_guest(&self) -> bool {
+        matches!(self.guest_expires_at, Some(expires_at) if expires_at <= token::now_secs())
+    }
+
+    // Invalidates every token issued so far
+    fn rotate_key(&mut self) -> HS256Key {
+        let key = HS256Key::generate();
+        self.key = key.to_bytes();
+        // Old tokens no longer verify, so their denylist entries are useless
+        self.revoked.clear();
+        key
+    }
+}
+
+// Keeps users in memory. If `path` is set, users are loaded from it on startup
+// and the whole map is written back as JSON after every change.
+// Failed login counters are never persisted.
+pub struct LocalAuthManager {
+    users: HashMap<String, LocalUser>,
+    path: Option<String>,
+    token_config: TokenConfig,
+    // key -> (attempts, expires at)
+    login_attempts: HashMap<String, (LoginAttempts, u64)>,
+}
+
+impl LocalAuthManager {
+    pub fn new(path: Option<String>, token_config: TokenConfig) -> LocalAuthManager {
+        let users = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => serde_json::from_str(&content).expect("Cannot parse auth file"),
+                Err(_) => {
+                    info!("Auth file {} not found, starting with no users", path);
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        LocalAuthManager {
+            users,
+            path,
+            token_config,
+            login_attempts: HashMap::new(),
+        }
+    }
+
+    fn persist(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp_path = path.to_owned() + ".tmp";
+        fs::write(&tmp_path, serde_json::to_string(&self.users)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuthManager for LocalAuthManager {
+    async fn register(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        if username.contains('_') {
+            return Err("Username contains illegal character: _".into());
+        }
+
+        if self.users.contains_key(&username) {
+            return Err("User already exists".into());
+        }
+
+        let key = HS256Key::generate();
+        let user = LocalUser {
+            password: password::hash_password(&password)?,
+            key: key.to_bytes(),
+            revoked: HashMap::new(),
+            roles: default_roles(),
+            reset: None,
+            totp: None,
+            pending_totp: None,
+            oidc_subject: None,
+            api_keys: Vec::new(),
+            guest_expires_at: None,
+        };
+        let roles = user.roles.clone();
+        self.users.insert(username.clone(), user);
+        self.persist()?;
+
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn login(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<LoginOutcome, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => {
+                password::dummy_verify(&password);
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let roles = user.roles.clone();
+        let two_factor = user.totp.is_some();
+        match password::verify_password(&password, &user.password) {
+            Verification::Valid => (),
+            Verification::ValidNeedsRehash => {
+                user.password = password::hash_password(&password)?;
+                self.persist()?;
+            }
+            Verification::Invalid => return Err(INVALID_CREDENTIALS.into()),
+        }
+        if two_factor {
+            let token = token::create_two_factor_token(&key, &self.token_config, &username)?;
+            return Ok(LoginOutcome::TwoFactorRequired(token));
+        }
+        let token = token::create_token(&key, &self.token_config, &username, &roles)?;
+        Ok(LoginOutcome::Token(token))
+    }
+
+    async fn verify_second_factor(
+        &mut self,
+        username: String,
+        two_factor_token: String,
+        code: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("invalid token".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims =
+            token::verify_two_factor_token(&key, &self.token_config, &username, &two_factor_token)?;
+        if user.is_revoked(&claims) {
+            return Err("invalid token".into());
+        }
+        let totp = match &mut user.totp {
+            Some(totp) => totp,
+            None => return Err("2FA is not enabled".into()),
+        };
+        if let Some(step) = two_factor::verify_code(&totp.secret, &code, totp.last_step) {
+            totp.last_step = step;
+        } else if let Some(i) = two_factor::find_recovery_code(&code, &totp.recovery_codes) {
+            totp.recovery_codes.remove(i);
+        } else {
+            return Err("Invalid code".into());
+        }
+        // The challenge can only be completed once
+        user.revoke(&claims, &self.token_config);
+        let roles = user.roles.clone();
+        self.persist()?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn create_guest(
+        &mut self,
+        lifetime: u64,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        let username = GUEST_PREFIX.to_owned() + &token::generate_id()[0..8];
+        if self.users.contains_key(&username) {
+            return Err("User already exists".into());
+        }
+
+        let key = HS256Key::generate();
+        let expires_at = token::now_secs() + lifetime;
+        let user = LocalUser {
+            // Nobody knows this password, guests only have their token
+            password: password::hash_password(&token::generate_id())?,
+            key: key.to_bytes(),
+            revoked: HashMap::new(),
+            roles: vec![Role::Player, Role::Guest],
+            reset: None,
+            totp: None,
+            pending_totp: None,
+            oidc_subject: None,
+            api_keys: Vec::new(),
+            guest_expires_at: Some(expires_at),
+        };
+        let roles = user.roles.clone();
+        self.users.insert(username.clone(), user);
+        self.persist()?;
+        let token =
+            token::create_guest_token(&key, &self.token_config, &username, &roles, expires_at)?;
+        Ok((username, token))
+    }
+
+    async fn count_guests(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        Ok(self
+            .users
+            .values()
+            .filter(|user| user.guest_expires_at.is_some() && !user.is_expired_guest())
+            .count())
+    }
+
+    async fn remove_expired_guests(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let expired: Vec<String> = self
+            .users
+            .iter()
+            .filter(|(_, user)| user.is_expired_guest())
+            .map(|(username, _)| username.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        for username in &expired {
+            self.users.remove(username);
+        }
+        self.persist()?;
+        Ok(expired)
+    }
+
+    async fn upgrade_guest(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) if user.guest_expires_at.is_some() && !user.is_expired_guest() => user,
+            _ => return Err("Not a guest".into()),
+        };
+        user.password = password::hash_password(&password)?;
+        user.guest_expires_at = None;
+        user.roles.retain(|role| *role != Role::Guest);
+        let key = user.rotate_key();
+        let roles = user.roles.clone();
+        self.persist()?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn find_linked_user(
+        &mut self,
+        subject: String,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .users
+            .iter()
+            .find(|(_, user)| user.oidc_subject.as_ref() == Some(&subject))
+            .map(|(username, _)| username.clone()))
+    }
+
+    async fn external_login(
+        &mut self,
+        subject: String,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let linked = self.find_linked_user(subject.clone()).await?;
+        match linked {
+            Some(linked) if linked == username => {
+                let user = &self.users[&username];
+                let key = HS256Key::from_bytes(&user.key);
+                return token::create_token(&key, &self.token_config, &username, &user.roles);
+            }
+            Some(_) => return Err("Identity is linked to another user".into()),
+            None => (),
+        }
+        if username.contains('_') {
+            return Err("Username contains illegal character: _".into());
+        }
+        if self.users.contains_key(&username) {
+            return Err("User already exists".into());
+        }
+
+        let key = HS256Key::generate();
+        let user = LocalUser {
+            // Nobody knows this password, the user logs in through the provider
+            password: password::hash_password(&token::generate_id())?,
+            key: key.to_bytes(),
+            revoked: HashMap::new(),
+            roles: default_roles(),
+            reset: None,
+            totp: None,
+            pending_totp: None,
+            oidc_subject: Some(subject),
+            api_keys: Vec::new(),
+            guest_expires_at: None,
+        };
+        let roles = user.roles.clone();
+        self.users.insert(username.clone(), user);
+        self.persist()?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn validate_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<TokenClaims, Box<dyn std::error::Error>> {
+        let user = match self.users.get(&username) {
+            Some(user) if !user.is_expired_guest() => user,
+            _ => return Err("invalid token".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims = token::verify_token(&key, &self.token_config, &username, &token)?;
+        if user.is_revoked(&claims) {
+            return Err("invalid token".into());
+        }
+        Ok(claims.custom)
+    }
+
+    async fn revoke_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims = token::verify_refreshable_token(&key, &self.token_config, &username, &token)?;
+        user.revoke(&claims, &self.token_config);
+        self.persist()
+    }
+
+    async fn refresh_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) if !user.is_expired_guest() => user,
+            _ => return Err("invalid token".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims = token::verify_refreshable_token(&key, &self.token_config, &username, &token)?;
+        if user.is_revoked(&claims) {
+            return Err("invalid token".into());
+        }
+
+        let new_token = match user.guest_expires_at {
+            Some(expires_at) => token::create_guest_token(
+                &key,
+                &self.token_config,
+                &username,
+                &user.roles,
+                expires_at,
+            )?,
+            None => {
+                token::refresh_token(&key, &self.token_config, &username, &user.roles, &claims)?
+            }
+        };
+        user.revoke(&claims, &self.token_config);
+        self.persist()?;
+        Ok(new_token)
+    }
+
+    async fn revoke_all_tokens(
+        &mut self,
+        username: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        user.rotate_key();
+        self.persist()
+    }
+
+    async fn change_password(
+        &mut self,
+        username: String,
+        current_password: Option<String>,
+        new_password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err(INVALID_CREDENTIALS.into()),
+        };
+        if let Some(current_password) = current_password {
+            if password::verify_password(&current_password, &user.password) == Verification::Invalid
+            {
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        }
+        user.password = password::hash_password(&new_password)?;
+        user.reset = None;
+        let key = user.rotate_key();
+        let roles = user.roles.clone();
+        self.persist()?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn create_password_reset(
+        &mut self,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        let reset_token = token::generate_id();
+        user.reset = Some(PasswordReset {
+            hash: password::hash_password(&reset_token)?,
+            expires_at: token::now_secs() + PASSWORD_RESET_TTL,
+        });
+        self.persist()?;
+        Ok(reset_token)
+    }
+
+    async fn reset_password(
+        &mut self,
+        username: String,
+        reset_token: String,
+        new_password: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("Invalid reset token".into()),
+        };
+        match &user.reset {
+            Some(reset)
+                if reset.expires_at > token::now_secs()
+                    && password::verify_password(&reset_token, &reset.hash)
+                        != Verification::Invalid => {}
+            _ => return Err("Invalid reset token".into()),
+        }
+        user.reset = None;
+        user.password = password::hash_password(&new_password)?;
+        user.rotate_key();
+        self.persist()
+    }
+
+    async fn delete_user(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get(&username) {
+            Some(user) => user,
+            None => return Err(INVALID_CREDENTIALS.into()),
+        };
+        if let Some(password) = password {
+            if password::verify_password(&password, &user.password) == Verification::Invalid {
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        }
+        self.users.remove(&username);
+        self.login_attempts
+            .remove(&login_throttle::user_key(&username));
+        self.persist()
+    }
+
+    async fn create_api_key(
+        &mut self,
+        username: String,
+        name: String,
+        scopes: Vec<ApiKeyScope>,
+    ) -> Result<(ApiKeyInfo, String), Box<dyn std::error::Error>> {
+        api_key::validate_name(&name)?;
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        if user.api_keys.len() >= api_key::MAX_API_KEYS {
+            return Err("Too many API keys".into());
+        }
+        let info = ApiKeyInfo {
+            id: token::generate_id(),
+            name,
+            scopes,
+            created_at: token::now_secs(),
+        };
+        let (key, hash) = api_key::generate(&info.id);
+        user.api_keys.push(StoredApiKey {
+            info: info.clone(),
+            hash,
+        });
+        self.persist()?;
+        Ok((info, key))
+    }
+
+    async fn list_api_keys(
+        &mut self,
+        username: String,
+    ) -> Result<Vec<ApiKeyInfo>, Box<dyn std::error::Error>> {
+        match self.users.get(&username) {
+            Some(user) => Ok(user.api_keys.iter().map(|key| key.info.clone()).collect()),
+            None => Err("User does not exist".into()),
+        }
+    }
+
+    async fn revoke_api_key(
+        &mut self,
+        username: String,
+        id: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        let count = user.api_keys.len();
+        user.api_keys.retain(|key| key.info.id != id);
+        if user.api_keys.len() == count {
+            return Err("API key does not exist".into());
+        }
+        self.persist()
+    }
+
+    async fn validate_api_key(
+        &mut self,
+        key: String,
+    ) -> Result<(UserInfo, Vec<ApiKeyScope>), Box<dyn std::error::Error>> {
+        let (id, secret) = match api_key::parse(&key) {
+            Some(parsed) => parsed,
+            None => return Err("invalid API key".into()),
+        };
+        for (username, user) in &self.users {
+            let stored = match user.api_keys.iter().find(|key| key.info.id == id) {
+                Some(stored) => stored,
+                None => continue,
+            };
+            if !api_key::verify_secret(secret, &stored.hash) {
+                break;
+            }
+            let owner = UserInfo {
+                username: username.clone(),
+                roles: user.roles.clone(),
+            };
+            return Ok((owner, stored.info.scopes.clone()));
+        }
+        Err("invalid API key".into())
+    }
+
+    async fn begin_totp_enrollment(
+        &mut self,
+        username: String,
+    ) -> Result<TotpEnrollment, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        if user.totp.is_some() {
+            return Err("2FA is already enabled".into());
+        }
+        let secret = two_factor::generate_secret();
+        let enrollment = two_factor::enrollment(&secret, &self.token_config.issuer, &username)?;
+        user.pending_totp = Some(PendingTotp {
+            secret,
+            expires_at: token::now_secs() + two_factor::ENROLLMENT_TTL,
+        });
+        self.persist()?;
+        Ok(enrollment)
+    }
+
+    async fn confirm_totp_enrollment(
+        &mut self,
+        username: String,
+        code: String,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        let secret = match &user.pending_totp {
+            Some(pending) if pending.expires_at > token::now_secs() => pending.secret.clone(),
+            _ => return Err("No pending 2FA enrollment".into()),
+        };
+        let last_step = match two_factor::verify_code(&secret, &code, 0) {
+            Some(step) => step,
+            None => return Err("Invalid code".into()),
+        };
+        let codes = two_factor::generate_recovery_codes();
+        let recovery_codes = codes
+            .iter()
+            .map(|code| two_factor::hash_recovery_code(code))
+            .collect();
+        user.totp = Some(Totp {
+            secret,
+            last_step,
+            recovery_codes,
+        });
+        user.pending_totp = None;
+        self.persist()?;
+        Ok(codes)
+    }
+
+    async fn disable_totp(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err(INVALID_CREDENTIALS.into()),
+        };
+        if let Some(password) = password {
+            if password::verify_password(&password, &user.password) == Verification::Invalid {
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        }
+        user.totp = None;
+        user.pending_totp = None;
+        self.persist()
+    }
+
+    async fn list_users(&mut self) -> Result<Vec<UserInfo>, Box<dyn std::error::Error>> {
+        let mut users: Vec<UserInfo> = self
+            .users
+            .iter()
+            .map(|(username, user)| UserInfo {
+                username: username.clone(),
+                roles: user.roles.clone(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    async fn grant_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        if !user.roles.contains(&role) {
+            user.roles.push(role);
+            user.roles.sort();
+        }
+        self.persist()
+    }
+
+    async fn revoke_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = match self.users.get_mut(&username) {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        user.roles.retain(|r| *r != role);
+        self.revoke_all_tokens(username).await
+    }
+
+    async fn get_login_attempts(
+        &mut self,
+        key: String,
+    ) -> Result<LoginAttempts, Box<dyn std::error::Error>> {
+        match self.login_attempts.get(&key) {
+            Some((attempts, expires_at)) if *expires_at > token::now_secs() => Ok(attempts.clone()),
+            _ => Ok(LoginAttempts::default()),
+        }
+    }
+
+    async fn record_failed_login(
+        &mut self,
+        key: String,
+        expire_after: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let now = token::now_secs();
+        self.login_attempts
+            .retain(|_, (_, expires_at)| *expires_at > now);
+        let (attempts, expires_at) = self
+            .login_attempts
+            .entry(key)
+            .or_insert((LoginAttempts::default(), 0));
+        attempts.failures += 1;
+        attempts.last_failure = now;
+        *expires_at = now + expire_after;
+        Ok(())
+    }
+
+    async fn clear_failed_logins(&mut self, key: String) -> Result<(), Box<dyn std::error::Error>> {
+        self.login_attempts.remove(&key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn session_token(outcome: LoginOutcome) -> String {
+        match outcome {
+            LoginOutcome::Token(token) => token,
+            LoginOutcome::TwoFactorRequired(_) => panic!("Unexpected 2FA challenge"),
+        }
+    }
+
+    fn two_factor_token(outcome: LoginOutcome) -> String {
+        match outcome {
+            LoginOutcome::Token(_) => panic!("2FA challenge expected"),
+            LoginOutcome::TwoFactorRequired(token) => token,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+
+        let token = session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_register_existing() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .register("alice".to_string(), "other".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_login_wrong_password() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .login("alice".to_string(), "hunter3".to_string())
+            .await
+            .is_err());
+        assert!(auth
+            .login("bob".to_string(), "hunter2".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_login_uniform_error() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let wrong_password = auth
+            .login("alice".to_string(), "hunter3".to_string())
+            .await
+            .unwrap_err();
+        let unknown_user = auth
+            .login("bob".to_string(), "hunter2".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(wrong_password.to_string(), unknown_user.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_failed_logins() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let key = "user:alice".to_string();
+        auth.record_failed_login(key.clone(), 60).await.unwrap();
+        auth.record_failed_login(key.clone(), 60).await.unwrap();
+        let attempts = auth.get_login_attempts(key.clone()).await.unwrap();
+        assert_eq!(attempts.failures, 2);
+
+        auth.clear_failed_logins(key.clone()).await.unwrap();
+        assert_eq!(
+            auth.get_login_attempts(key.clone()).await.unwrap(),
+            LoginAttempts::default()
+        );
+
+        auth.record_failed_login(key.clone(), 0).await.unwrap();
+        assert_eq!(
+            auth.get_login_attempts(key).await.unwrap(),
+            LoginAttempts::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_token_other_user() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        auth.register("bob".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("bob".to_string(), token.clone())
+            .await
+            .is_err());
+        assert!(auth
+            .validate_token("carol".to_string(), token)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_token() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let first = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let second = session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        auth.revoke_token("alice".to_string(), first.clone())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), first)
+            .await
+            .is_err());
+        assert!(auth
+            .validate_token("alice".to_string(), second)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_all_tokens() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let first = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let second = session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        auth.revoke_all_tokens("alice".to_string()).await.unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), first)
+            .await
+            .is_err());
+        assert!(auth
+            .validate_token("alice".to_string(), second)
+            .await
+            .is_err());
+
+        let third = session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        assert!(auth
+            .validate_token("alice".to_string(), third)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let refreshed = auth
+            .refresh_token("alice".to_string(), token.clone())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), refreshed)
+            .await
+            .is_ok());
+        // The refreshed token cannot be used nor refreshed again
+        assert!(auth
+            .validate_token("alice".to_string(), token.clone())
+            .await
+            .is_err());
+        assert!(auth
+            .refresh_token("alice".to_string(), token)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_roles() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let claims = auth
+            .validate_token("alice".to_string(), token.clone())
+            .await
+            .unwrap();
+        assert_eq!(claims.roles, vec![Role::Player]);
+
+        auth.grant_role("alice".to_string(), Role::Admin)
+            .await
+            .unwrap();
+        assert_eq!(
+            auth.list_users().await.unwrap(),
+            vec![UserInfo {
+                username: "alice".to_string(),
+                roles: vec![Role::Player, Role::Admin],
+            }]
+        );
+        let token = auth
+            .refresh_token("alice".to_string(), token)
+            .await
+            .unwrap();
+        let claims = auth
+            .validate_token("alice".to_string(), token.clone())
+            .await
+            .unwrap();
+        assert_eq!(claims.roles, vec![Role::Player, Role::Admin]);
+
+        auth.revoke_role("alice".to_string(), Role::Admin)
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_err());
+        assert!(auth
+            .grant_role("bob".to_string(), Role::Admin)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_change_password() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let old_token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .change_password(
+                "alice".to_string(),
+                Some("hunter3".to_string()),
+                "hunter4".to_string()
+            )
+            .await
+            .is_err());
+        let token = auth
+            .change_password(
+                "alice".to_string(),
+                Some("hunter2".to_string()),
+                "hunter4".to_string(),
+            )
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), old_token)
+            .await
+            .is_err());
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+        assert!(auth
+            .login("alice".to_string(), "hunter2".to_string())
+            .await
+            .is_err());
+        assert!(auth
+            .login("alice".to_string(), "hunter4".to_string())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reset_password() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let reset_token = auth
+            .create_password_reset("alice".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .reset_password(
+                "alice".to_string(),
+                "guess".to_string(),
+                "hunter4".to_string()
+            )
+            .await
+            .is_err());
+        auth.reset_password(
+            "alice".to_string(),
+            reset_token.clone(),
+            "hunter4".to_string(),
+        )
+        .await
+        .unwrap();
+        assert!(auth
+            .login("alice".to_string(), "hunter4".to_string())
+            .await
+            .is_ok());
+        // Reset tokens can only be used once
+        assert!(auth
+            .reset_password("alice".to_string(), reset_token, "hunter5".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .delete_user("alice".to_string(), Some("hunter3".to_string()))
+            .await
+            .is_err());
+        auth.delete_user("alice".to_string(), Some("hunter2".to_string()))
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_err());
+        assert!(auth.list_users().await.unwrap().is_empty());
+        assert!(auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_two_factor() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let enrollment = auth
+            .begin_totp_enrollment("alice".to_string())
+            .await
+            .unwrap();
+        // Not enabled until confirmed
+        session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        assert!(auth
+            .confirm_totp_enrollment("alice".to_string(), "000000x".to_string())
+            .await
+            .is_err());
+        let code = two_factor::current_code(&enrollment.secret);
+        let recovery_codes = auth
+            .confirm_totp_enrollment("alice".to_string(), code.clone())
+            .await
+            .unwrap();
+
+        let challenge = two_factor_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        // The challenge is not a session token
+        assert!(auth
+            .validate_token("alice".to_string(), challenge.clone())
+            .await
+            .is_err());
+        // The code used for enrollment cannot be replayed
+        assert!(auth
+            .verify_second_factor("alice".to_string(), challenge.clone(), code)
+            .await
+            .is_err());
+        let token = auth
+            .verify_second_factor(
+                "alice".to_string(),
+                challenge.clone(),
+                recovery_codes[0].clone(),
+            )
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+        // Neither the challenge nor the recovery code can be used twice
+        assert!(auth
+            .verify_second_factor("alice".to_string(), challenge, recovery_codes[1].clone())
+            .await
+            .is_err());
+        let challenge = two_factor_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        assert!(auth
+            .verify_second_factor("alice".to_string(), challenge, recovery_codes[0].clone())
+            .await
+            .is_err());
+
+        assert!(auth
+            .disable_totp("alice".to_string(), Some("hunter3".to_string()))
+            .await
+            .is_err());
+        auth.disable_totp("alice".to_string(), Some("hunter2".to_string()))
+            .await
+            .unwrap();
+        session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_external_login() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.register("bob".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let subject = "https://idp.example|1234".to_string();
+        assert_eq!(auth.find_linked_user(subject.clone()).await.unwrap(), None);
+        // Existing local users cannot be taken over
+        assert!(auth
+            .external_login(subject.clone(), "bob".to_string())
+            .await
+            .is_err());
+
+        let token = auth
+            .external_login(subject.clone(), "alice".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+        assert_eq!(
+            auth.find_linked_user(subject.clone()).await.unwrap(),
+            Some("alice".to_string())
+        );
+        assert!(auth
+            .external_login(subject.clone(), "alice".to_string())
+            .await
+            .is_ok());
+        assert!(auth
+            .external_login(subject, "carol".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_external_user_account() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.external_login("https://idp.example|1234".to_string(), "alice".to_string())
+            .await
+            .unwrap();
+        // A recent login through the provider stands in for the password
+        auth.change_password("alice".to_string(), None, "hunter2".to_string())
+            .await
+            .unwrap();
+        auth.login("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        auth.delete_user("alice".to_string(), None).await.unwrap();
+        assert!(auth.list_users().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .create_api_key("alice".to_string(), "".to_string(), vec![])
+            .await
+            .is_err());
+        let (info, key) = auth
+            .create_api_key(
+                "alice".to_string(),
+                "grading".to_string(),
+                vec![ApiKeyScope::Proxy],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            auth.list_api_keys("alice".to_string()).await.unwrap(),
+            vec![info.clone()]
+        );
+
+        let (owner, scopes) = auth.validate_api_key(key.clone()).await.unwrap();
+        assert_eq!(owner.username, "alice");
+        assert_eq!(scopes, vec![ApiKeyScope::Proxy]);
+        let forged = format!("lynx_{}_{}", info.id, token::generate_id());
+        assert!(auth.validate_api_key(forged).await.is_err());
+
+        assert!(auth
+            .revoke_api_key("bob".to_string(), info.id.clone())
+            .await
+            .is_err());
+        auth.revoke_api_key("alice".to_string(), info.id)
+            .await
+            .unwrap();
+        assert!(auth.validate_api_key(key).await.is_err());
+        assert!(auth
+            .list_api_keys("alice".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_guests() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let (username, token) = auth.create_guest(3600).await.unwrap();
+        assert!(username.starts_with(GUEST_PREFIX));
+        let claims = auth
+            .validate_token(username.clone(), token.clone())
+            .await
+            .unwrap();
+        assert_eq!(claims.roles, vec![Role::Player, Role::Guest]);
+        assert_eq!(auth.count_guests().await.unwrap(), 1);
+        assert!(auth
+            .upgrade_guest("alice".to_string(), "hunter2".to_string())
+            .await
+            .is_err());
+
+        let upgraded = auth
+            .upgrade_guest(username.clone(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth.validate_token(username.clone(), token).await.is_err());
+        let claims = auth
+            .validate_token(username.clone(), upgraded)
+            .await
+            .unwrap();
+        assert_eq!(claims.roles, vec![Role::Player]);
+        assert!(auth.login(username, "hunter2".to_string()).await.is_ok());
+        assert_eq!(auth.count_guests().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_expired_guests() {
+        let mut auth = LocalAuthManager::new(None, TokenConfig::default());
+        let (expired, token) = auth.create_guest(0).await.unwrap();
+        let (active, _) = auth.create_guest(3600).await.unwrap();
+        assert!(auth.validate_token(expired.clone(), token).await.is_err());
+        assert_eq!(auth.count_guests().await.unwrap(), 1);
+        assert_eq!(
+            auth.remove_expired_guests().await.unwrap(),
+            vec![expired.clone()]
+        );
+        let usernames: Vec<String> = auth
+            .list_users()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.username)
+            .collect();
+        assert_eq!(usernames, vec![active]);
+        assert!(auth
+            .upgrade_guest(expired, "hunter2".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_persist() {
+        let path = env::temp_dir()
+            .join(format!("lynx-auth-{}.json", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut auth = LocalAuthManager::new(Some(path.clone()), TokenConfig::default());
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+
+        let mut auth = LocalAuthManager::new(Some(path.clone()), TokenConfig::default());
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+        assert!(auth
+            .login("alice".to_string(), "hunter2".to_string())
+            .await
+            .is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
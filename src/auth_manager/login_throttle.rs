@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+
+// Failed logins are counted separately per username and per client IP.
+// After `free_attempts` failures every further attempt has to wait
+// `base_delay` * 2^(failures - free_attempts) seconds since the last failure,
+// capped at `max_delay`, which acts as a temporary lockout.
+// Counters are forgotten `reset_after` seconds after the last failure.
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LoginAttempts {
+    pub failures: u32,
+    pub last_failure: u64, // unix timestamp
+}
+
+#[derive(Clone, Debug)]
+pub struct LoginThrottle {
+    pub free_attempts: u32,
+    pub base_delay: u64,
+    pub max_delay: u64,
+    pub reset_after: u64,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> LoginThrottle {
+        LoginThrottle {
+            free_attempts: 3,
+            base_delay: 1,
+            max_delay: 15 * 60,
+            reset_after: 60 * 60,
+        }
+    }
+}
+
+impl LoginThrottle {
+    // Seconds to wait before the next attempt is allowed, if any
+    pub fn retry_after(&self, attempts: &LoginAttempts, now: u64) -> Option<u64> {
+        if attempts.failures < self.free_attempts {
+            return None;
+        }
+        if now >= attempts.last_failure + self.reset_after {
+            return None;
+        }
+
+        let exponent = (attempts.failures - self.free_attempts).min(32);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let allowed_at = attempts.last_failure + delay;
+        if now < allowed_at {
+            Some(allowed_at - now)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn user_key(username: &str) -> String {
+    "user:".to_owned() + username
+}
+
+pub fn ip_key(ip: &str) -> String {
+    "ip:".to_owned() + ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attempts(failures: u32, last_failure: u64) -> LoginAttempts {
+        LoginAttempts {
+            failures,
+            last_failure,
+        }
+    }
+
+    #[test]
+    fn test_free_attempts() {
+        let throttle = LoginThrottle::default();
+        assert_eq!(throttle.retry_after(&attempts(0, 1000), 1000), None);
+        assert_eq!(throttle.retry_after(&attempts(2, 1000), 1000), None);
+        assert_eq!(throttle.retry_after(&attempts(3, 1000), 1000), Some(1));
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let throttle = LoginThrottle::default();
+        assert_eq!(throttle.retry_after(&attempts(4, 1000), 1000), Some(2));
+        assert_eq!(throttle.retry_after(&attempts(6, 1000), 1000), Some(8));
+        assert_eq!(throttle.retry_after(&attempts(6, 1000), 1005), Some(3));
+        assert_eq!(throttle.retry_after(&attempts(6, 1000), 1008), None);
+    }
+
+    #[test]
+    fn test_lockout() {
+        let throttle = LoginThrottle::default();
+        assert_eq!(
+            throttle.retry_after(&attempts(100, 1000), 1000),
+            Some(throttle.max_delay)
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let throttle = LoginThrottle::default();
+        let now = 1000 + throttle.reset_after;
+        assert_eq!(throttle.retry_after(&attempts(100, 1000), now), None);
+    }
+}
@@ -1,8 +1,45 @@
+pub mod api_key;
+pub mod authenticated_user;
+pub mod local_auth_manager;
+pub mod login_throttle;
+pub mod oidc;
+pub mod password;
 pub mod redis_auth_manager;
+pub mod role;
+pub mod signing_keys;
+pub mod sqlite_auth_manager;
+pub mod token;
+pub mod two_factor;
+pub mod username_policy;
+
+use crate::auth_manager::api_key::{ApiKeyInfo, ApiKeyScope};
+use crate::auth_manager::login_throttle::LoginAttempts;
+use crate::auth_manager::role::Role;
+use crate::auth_manager::token::TokenClaims;
+use crate::auth_manager::two_factor::TotpEnrollment;
 
-use actix_session::Session;
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UserInfo {
+    pub username: String,
+    pub roles: Vec<Role>,
+}
 
+// Returned for both unknown users and wrong passwords
+pub const INVALID_CREDENTIALS: &str = "Invalid username or password";
+// Seconds an admin-issued password reset token stays valid
+pub const PASSWORD_RESET_TTL: u64 = 60 * 60;
+pub const GUEST_PREFIX: &str = "guest-";
+
+#[derive(Debug)]
+pub enum LoginOutcome {
+    Token(String),
+    // The password was correct, but 2FA is enabled. The contained token
+    // has to be passed to `verify_second_factor` together with a code.
+    TwoFactorRequired(String),
+}
 
 #[async_trait]
 pub trait AuthManager {
@@ -10,41 +47,156 @@ pub trait AuthManager {
         &mut self,
         username: String,
         password: String,
+    ) -> Result<LoginOutcome, Box<dyn std::error::Error>>;
+    // Accepts either a TOTP code or one of the recovery codes
+    async fn verify_second_factor(
+        &mut self,
+        username: String,
+        two_factor_token: String,
+        code: String,
     ) -> Result<String, Box<dyn std::error::Error>>;
     async fn register(
         &mut self,
         username: String,
         password: String,
     ) -> Result<String, Box<dyn std::error::Error>>;
+    // Creates a user with a random name, without password, which is deleted
+    // once `lifetime` seconds have passed. Returns (username, token).
+    async fn create_guest(
+        &mut self,
+        lifetime: u64,
+    ) -> Result<(String, String), Box<dyn std::error::Error>>;
+    async fn count_guests(&mut self) -> Result<usize, Box<dyn std::error::Error>>;
+    // Deletes the expired guests and returns their names,
+    // so that their instances can be stopped
+    async fn remove_expired_guests(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+    // Turns a guest into a regular user with the given password,
+    // keeping the username. Tokens issued to the guest are revoked.
+    async fn upgrade_guest(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+    // The user an identity provider subject was linked to on first login
+    async fn find_linked_user(
+        &mut self,
+        subject: String,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>>;
+    // Creates a user without usable password, linked to `subject`, unless
+    // `username` is linked to it already
+    async fn external_login(
+        &mut self,
+        subject: String,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>>;
     async fn validate_token(
         &mut self,
         username: String,
         token: String,
+    ) -> Result<TokenClaims, Box<dyn std::error::Error>>;
+    // Issues a new token if the current one is valid or expired less than the
+    // refresh grace period ago. The current token is revoked.
+    async fn refresh_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+    // Denylists the token until it could no longer be refreshed
+    async fn revoke_token(
+        &mut self,
+        username: String,
+        token: String,
     ) -> Result<(), Box<dyn std::error::Error>>;
-}
-
-pub async fn authorize_from_session(
-    session: &Session,
-    auth_manager: &mut Box<dyn AuthManager + Sync + Send>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ret = session.get::<String>("session_token");
-    if let Err(_) = ret {
-        return Err("Cannot get session token".into());
-    }
-    let ret = ret.unwrap();
-    if let None = ret {
-        return Err("Cannot get session token".into());
-    }
-    let token = ret.unwrap();
-    let ret = session.get::<String>("session_username");
-    if let Err(_) = ret {
-        return Err("Cannot get session username".into());
-    }
-    let ret = ret.unwrap();
-    if let None = ret {
-        return Err("Cannot get session username".into());
-    }
-    let username = ret.unwrap();
-
-    auth_manager.validate_token(username, token).await
+    // Rotates the user's key, which invalidates every token issued so far
+    async fn revoke_all_tokens(
+        &mut self,
+        username: String,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    // Returns a new token, all other tokens of the user are revoked.
+    // The current password is `None` when the user proved their identity
+    // otherwise, i.e. by a recent OIDC login, as OIDC accounts have none.
+    async fn change_password(
+        &mut self,
+        username: String,
+        current_password: Option<String>,
+        new_password: String,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+    // Returns a one-time token for `reset_password`, replacing any previous one
+    async fn create_password_reset(
+        &mut self,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+    async fn reset_password(
+        &mut self,
+        username: String,
+        reset_token: String,
+        new_password: String,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    // Removes all of the user's auth entries, `password` as for `change_password`
+    async fn delete_user(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    // Returns the key itself, which is not stored and cannot be shown again
+    async fn create_api_key(
+        &mut self,
+        username: String,
+        name: String,
+        scopes: Vec<ApiKeyScope>,
+    ) -> Result<(ApiKeyInfo, String), Box<dyn std::error::Error>>;
+    async fn list_api_keys(
+        &mut self,
+        username: String,
+    ) -> Result<Vec<ApiKeyInfo>, Box<dyn std::error::Error>>;
+    async fn revoke_api_key(
+        &mut self,
+        username: String,
+        id: String,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    // Returns the owner of the key and what the key may be used for
+    async fn validate_api_key(
+        &mut self,
+        key: String,
+    ) -> Result<(UserInfo, Vec<ApiKeyScope>), Box<dyn std::error::Error>>;
+    // 2FA is enabled only after `confirm_totp_enrollment`
+    async fn begin_totp_enrollment(
+        &mut self,
+        username: String,
+    ) -> Result<TotpEnrollment, Box<dyn std::error::Error>>;
+    // Returns the recovery codes, replacing any previous ones
+    async fn confirm_totp_enrollment(
+        &mut self,
+        username: String,
+        code: String,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+    async fn disable_totp(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    async fn list_users(&mut self) -> Result<Vec<UserInfo>, Box<dyn std::error::Error>>;
+    async fn grant_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    // Also revokes all of the user's tokens, as they still carry the role
+    async fn revoke_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    // Failed login counters, keyed by `login_throttle::user_key`/`ip_key`
+    async fn get_login_attempts(
+        &mut self,
+        key: String,
+    ) -> Result<LoginAttempts, Box<dyn std::error::Error>>;
+    // Counts a failure, the counter is dropped `expire_after` seconds later
+    async fn record_failed_login(
+        &mut self,
+        key: String,
+        expire_after: u64,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    async fn clear_failed_logins(&mut self, key: String) -> Result<(), Box<dyn std::error::Error>>;
 }
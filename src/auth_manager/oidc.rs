@@ -0,0 +1,411 @@
+use crate::auth_manager::token;
+
+use jwt_simple::prelude::*;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::sync::RwLock;
+use subtle::ConstantTimeEq;
+use url::Url;
+
+// OpenID Connect authorization code flow with PKCE (RFC 7636).
+// The provider is discovered once on startup, its signing keys are fetched
+// again whenever an ID token names a key ID not seen before.
+
+// A slow provider must not hold up logins for long
+const HTTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    // Not needed for public clients, PKCE protects the code anyway
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: String,
+    // ID token claim the local username is taken from on first login
+    pub username_claim: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// Kept in the session between `/auth/oidc/start` and `/auth/oidc/callback`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuthorizationRequest {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    // `<issuer>|<sub>`, unique across providers
+    pub subject: String,
+    pub username: Option<String>,
+}
+
+// Shared by concurrent logins, which replace the keys when they are rotated
+pub struct OidcProvider {
+    config: OidcConfig,
+    metadata: ProviderMetadata,
+    keys: RwLock<Vec<Jwk>>,
+}
+
+fn http_client() -> awc::Client {
+    awc::Client::builder().timeout(HTTP_TIMEOUT).finish()
+}
+
+impl OidcProvider {
+    pub async fn discover(config: OidcConfig) -> Result<OidcProvider, Box<dyn std::error::Error>> {
+        let url =
+            config.issuer.trim_end_matches('/').to_owned() + "/.well-known/openid-configuration";
+        let metadata: ProviderMetadata = http_client().get(url).send().await?.json().await?;
+        if metadata.issuer != config.issuer {
+            return Err(format!("Provider claims to be {}", metadata.issuer).into());
+        }
+        let provider = OidcProvider {
+            config,
+            metadata,
+            keys: RwLock::new(Vec::new()),
+        };
+        provider.fetch_keys().await?;
+        Ok(provider)
+    }
+
+    async fn fetch_keys(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let jwks: JwkSet = http_client()
+            .get(&self.metadata.jwks_uri)
+            .send()
+            .await?
+            .json()
+            .await?;
+        *self.keys.write().unwrap() = jwks.keys;
+        Ok(())
+    }
+
+    // The URL to redirect the browser to
+    pub fn authorization_url(
+        &self,
+    ) -> Result<(String, AuthorizationRequest), Box<dyn std::error::Error>> {
+        let request = AuthorizationRequest {
+            state: token::generate_id(),
+            nonce: token::generate_id(),
+            code_verifier: token::generate_id() + &token::generate_id(),
+        };
+        let url = Url::parse_with_params(
+            &self.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", &self.config.scopes),
+                ("state", &request.state),
+                ("nonce", &request.nonce),
+                ("code_challenge", &code_challenge(&request.code_verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+        Ok((url.to_string(), request))
+    }
+
+    // Redeems the code passed to the callback, `state` has to be checked before
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        request: &AuthorizationRequest,
+    ) -> Result<Identity, Box<dyn std::error::Error>> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &request.code_verifier),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let mut response = http_client()
+            .post(&self.metadata.token_endpoint)
+            .send_form(&form)
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("Token endpoint answered {}", response.status()).into());
+        }
+        let response: TokenResponse = response.json().await?;
+
+        let kid = Token::decode_metadata(&response.id_token)?
+            .key_id()
+            .map(str::to_string);
+        if self.find_key(kid.as_deref()).is_none() {
+            self.fetch_keys().await?;
+        }
+        self.verify_id_token(&response.id_token, kid.as_deref(), &request.nonce)
+    }
+
+    fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = self.keys.read().unwrap();
+        match kid {
+            Some(kid) => keys
+                .iter()
+                .find(|key| key.kid.as_deref() == Some(kid))
+                .cloned(),
+            None if keys.len() == 1 => keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    fn verify_id_token(
+        &self,
+        id_token: &str,
+        kid: Option<&str>,
+        nonce: &str,
+    ) -> Result<Identity, Box<dyn std::error::Error>> {
+        let key = match self.find_key(kid) {
+            Some(key) => key,
+            None => return Err("ID token signed with unknown key".into()),
+        };
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from([self.metadata.issuer.clone()])),
+            allowed_audiences: Some(HashSet::from([self.config.client_id.clone()])),
+            required_nonce: Some(nonce.to_string()),
+            ..Default::default()
+        };
+        let claims: JWTClaims<Map<String, Value>> = match (key.kty.as_str(), key.crv.as_deref()) {
+            ("RSA", _) => {
+                let n = decode_component(&key.n)?;
+                let e = decode_component(&key.e)?;
+                RS256PublicKey::from_components(&n, &e)?.verify_token(id_token, Some(options))?
+            }
+            ("EC", Some("P-256")) => {
+                let mut point = vec![0x04];
+                point.extend(decode_component(&key.x)?);
+                point.extend(decode_component(&key.y)?);
+                ES256PublicKey::from_bytes(&point)?.verify_token(id_token, Some(options))?
+            }
+            _ => return Err(format!("Unsupported key type {}", key.kty).into()),
+        };
+
+        let sub = match claims.subject {
+            Some(sub) => sub,
+            None => return Err("ID token has no subject".into()),
+        };
+        Ok(Identity {
+            subject: self.metadata.issuer.clone() + "|" + &sub,
+            username: claims
+                .custom
+                .get(&self.config.username_claim)
+                .and_then(Value::as_str)
+                .map(str::to_lowercase),
+        })
+    }
+}
+
+pub fn check_state(request: &AuthorizationRequest, state: &str) -> bool {
+    bool::from(request.state.as_bytes().ct_eq(state.as_bytes()))
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    let hash = hmac_sha256::Hash::hash(code_verifier.as_bytes());
+    Base64UrlSafeNoPadding::encode_to_string(hash).unwrap()
+}
+
+fn decode_component(component: &Option<String>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match component {
+        Some(component) => Ok(Base64UrlSafeNoPadding::decode_to_vec(component, None)?),
+        None => Err("Incomplete JWK".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // A provider which hands out one code, for the request it last saw
+    struct MockProvider {
+        issuer: String,
+        key: ES256KeyPair,
+        // code -> (code_challenge, nonce)
+        codes: Mutex<HashMap<String, (String, String)>>,
+    }
+
+    async fn discovery(provider: web::Data<MockProvider>) -> HttpResponse {
+        HttpResponse::Ok().json(serde_json::json!({
+            "issuer": provider.issuer,
+            "authorization_endpoint": provider.issuer.clone() + "/authorize",
+            "token_endpoint": provider.issuer.clone() + "/token",
+            "jwks_uri": provider.issuer.clone() + "/jwks",
+        }))
+    }
+
+    async fn jwks(provider: web::Data<MockProvider>) -> HttpResponse {
+        let point = provider
+            .key
+            .public_key()
+            .public_key()
+            .to_bytes_uncompressed();
+        HttpResponse::Ok().json(serde_json::json!({ "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "mock",
+            "x": Base64UrlSafeNoPadding::encode_to_string(&point[1..33]).unwrap(),
+            "y": Base64UrlSafeNoPadding::encode_to_string(&point[33..65]).unwrap(),
+        }]}))
+    }
+
+    async fn token(
+        provider: web::Data<MockProvider>,
+        form: web::Form<HashMap<String, String>>,
+    ) -> HttpResponse {
+        let code = form.get("code").cloned().unwrap_or_default();
+        let (challenge, nonce) = match provider.codes.lock().unwrap().remove(&code) {
+            Some(entry) => entry,
+            None => return HttpResponse::BadRequest().finish(),
+        };
+        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+        if code_challenge(&verifier) != challenge || form.get("client_id").unwrap() != "lynx" {
+            return HttpResponse::BadRequest().finish();
+        }
+        let mut custom = Map::new();
+        custom.insert("preferred_username".to_string(), "Alice".into());
+        let claims = Claims::with_custom_claims(custom, Duration::from_mins(5))
+            .with_issuer(&provider.issuer)
+            .with_audience("lynx")
+            .with_subject("1234")
+            .with_nonce(nonce);
+        let id_token = provider.key.sign(claims).unwrap();
+        HttpResponse::Ok().json(serde_json::json!({
+            "access_token": "unused",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        }))
+    }
+
+    async fn start_mock_provider() -> web::Data<MockProvider> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let provider = web::Data::new(MockProvider {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            key: ES256KeyPair::generate().with_key_id("mock"),
+            codes: Mutex::new(HashMap::new()),
+        });
+        let data = provider.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(discovery),
+                )
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        provider
+    }
+
+    fn config(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: "lynx".to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost:8080/auth/oidc/callback".to_string(),
+            scopes: "openid profile".to_string(),
+            username_claim: "preferred_username".to_string(),
+        }
+    }
+
+    // Plays the part of the browser and the user consenting at the provider
+    fn authorize(mock: &MockProvider, url: &str) -> String {
+        let url = Url::parse(url).unwrap();
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        let code = token::generate_id();
+        mock.codes.lock().unwrap().insert(
+            code.clone(),
+            (params["code_challenge"].clone(), params["nonce"].clone()),
+        );
+        code
+    }
+
+    #[actix_web::test]
+    async fn test_login() {
+        let mock = start_mock_provider().await;
+        let provider = OidcProvider::discover(config(&mock.issuer)).await.unwrap();
+        let (url, request) = provider.authorization_url().unwrap();
+        assert!(url.starts_with(&(mock.issuer.clone() + "/authorize?")));
+        assert!(check_state(&request, &request.state));
+        assert!(!check_state(&request, "forged"));
+
+        let code = authorize(&mock, &url);
+        let identity = provider.exchange_code(&code, &request).await.unwrap();
+        assert_eq!(
+            identity,
+            Identity {
+                subject: mock.issuer.clone() + "|1234",
+                username: Some("alice".to_string()),
+            }
+        );
+        // Codes are single use
+        assert!(provider.exchange_code(&code, &request).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_wrong_verifier_and_nonce() {
+        let mock = start_mock_provider().await;
+        let provider = OidcProvider::discover(config(&mock.issuer)).await.unwrap();
+        let (url, request) = provider.authorization_url().unwrap();
+
+        let code = authorize(&mock, &url);
+        let stolen = AuthorizationRequest {
+            code_verifier: "guess".to_string(),
+            ..request.clone()
+        };
+        assert!(provider.exchange_code(&code, &stolen).await.is_err());
+
+        let code = authorize(&mock, &url);
+        let replayed = AuthorizationRequest {
+            nonce: "other".to_string(),
+            ..request
+        };
+        assert!(provider.exchange_code(&code, &replayed).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_discover_unknown_issuer() {
+        let mock = start_mock_provider().await;
+        let other = config(&(mock.issuer.clone() + "/other"));
+        assert!(OidcProvider::discover(other).await.is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use argon2::password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
+
+// Passwords are stored as argon2id PHC strings, e.g.
+// $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+// Entries which do not parse as PHC strings are treated as legacy plaintext.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Valid,
+    // Password matches, but the stored entry should be replaced with `hash_password`
+    ValidNeedsRehash,
+    Invalid,
+}
+
+fn hasher() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+pub fn hash_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let salt = SaltString::generate(&mut OsRng);
+    match hasher().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(("Error hashing password: ".to_owned() + &e.to_string()).into()),
+    }
+}
+
+pub fn verify_password(password: &str, stored: &str) -> Verification {
+    let hash = match PasswordHash::new(stored) {
+        Ok(hash) => hash,
+        Err(_) => return verify_plaintext(password, stored),
+    };
+
+    if hasher()
+        .verify_password(password.as_bytes(), &hash)
+        .is_err()
+    {
+        return Verification::Invalid;
+    }
+
+    if is_outdated(&hash) {
+        Verification::ValidNeedsRehash
+    } else {
+        Verification::Valid
+    }
+}
+
+// Spends as much time as verifying a real password, so that unknown users
+// cannot be told apart from wrong passwords by response time
+pub fn dummy_verify(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY_HASH.get_or_init(|| hash_password("dummy").unwrap());
+    let _ = verify_password(password, hash);
+}
+
+pub fn is_hashed(stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok()
+}
+
+fn verify_plaintext(password: &str, stored: &str) -> Verification {
+    if bool::from(password.as_bytes().ct_eq(stored.as_bytes())) {
+        Verification::ValidNeedsRehash
+    } else {
+        Verification::Invalid
+    }
+}
+
+fn is_outdated(hash: &PasswordHash) -> bool {
+    if hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    if hash.version != Some(Version::V0x13.into()) {
+        return true;
+    }
+    let current = Params::default();
+    match Params::try_from(hash) {
+        Ok(params) => {
+            params.m_cost() != current.m_cost()
+                || params.t_cost() != current.t_cost()
+                || params.p_cost() != current.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_phc_string() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(is_hashed(&hash));
+    }
+
+    #[test]
+    fn test_salts_differ() {
+        let first = hash_password("hunter2").unwrap();
+        let second = hash_password("hunter2").unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_verify() {
+        let hash = hash_password("hunter2").unwrap();
+        assert_eq!(verify_password("hunter2", &hash), Verification::Valid);
+        assert_eq!(verify_password("hunter3", &hash), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_verify_plaintext() {
+        assert!(!is_hashed("hunter2"));
+        assert_eq!(
+            verify_password("hunter2", "hunter2"),
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(verify_password("hunter3", "hunter2"), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_verify_outdated_params() {
+        let params = Params::new(8 * 1024, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2
+            .hash_password("hunter2".as_bytes(), &salt)
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            verify_password("hunter2", &hash),
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(verify_password("hunter3", &hash), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_verify_outdated_algorithm() {
+        let argon2 = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::default());
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2
+            .hash_password("hunter2".as_bytes(), &salt)
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            verify_password("hunter2", &hash),
+            Verification::ValidNeedsRehash
+        );
+    }
+}
@@ -1,30 +1,223 @@
-use crate::auth_manager::AuthManager;
-
-
+use crate::auth_manager::api_key::{self, ApiKeyInfo, ApiKeyScope};
+use crate::auth_manager::login_throttle::LoginAttempts;
+use crate::auth_manager::password::{self, Verification};
+use crate::auth_manager::role::Role;
+use crate::auth_manager::token::{self, TokenClaims, TokenConfig};
+use crate::auth_manager::two_factor::{self, TotpEnrollment};
+use crate::auth_manager::{
+    login_throttle, AuthManager, LoginOutcome, UserInfo, GUEST_PREFIX, INVALID_CREDENTIALS,
+    PASSWORD_RESET_TTL,
+};
 
 use async_trait::async_trait;
+use redis::AsyncCommands;
 use redis::{aio::Connection, RedisError, RedisResult};
-use redis::{AsyncCommands};
+use std::str::FromStr;
+use tracing::{error, info};
 
-use jwt_simple::prelude::*;
+use jwt_simple::prelude::{HS256Key, JWTClaims};
 
 pub struct RedisAuthManager {
     con: Connection,
+    token_config: TokenConfig,
 }
 
 impl RedisAuthManager {
-    pub async fn new(url: String) -> RedisAuthManager {
+    pub async fn new(url: String, token_config: TokenConfig) -> RedisAuthManager {
         println!("{}", url);
         let client = redis::Client::open(url).unwrap();
         let con = client.get_async_connection().await.unwrap();
 
-        RedisAuthManager { con }
+        RedisAuthManager { con, token_config }
+    }
+
+    async fn is_revoked(&mut self, username: &str, claims: &JWTClaims<TokenClaims>) -> bool {
+        let jti = claims.jwt_id.as_ref().unwrap();
+        self.con
+            .exists(username.to_owned() + "_revoked_" + jti)
+            .await
+            .unwrap_or(true)
+    }
+
+    async fn revoke(
+        &mut self,
+        username: &str,
+        claims: &JWTClaims<TokenClaims>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ttl = token::denylist_ttl(claims, &self.token_config);
+        if ttl == 0 {
+            return Ok(());
+        }
+        let jti = claims.jwt_id.as_ref().unwrap();
+        let _: () = self
+            .con
+            .set_ex(username.to_owned() + "_revoked_" + jti, 1, ttl as usize)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_key(&mut self, username: &str) -> Result<HS256Key, Box<dyn std::error::Error>> {
+        let ret: Option<Vec<u8>> = self.con.get(username.to_owned() + "_key").await?;
+        match ret {
+            Some(bytes) => Ok(HS256Key::from_bytes(&bytes)),
+            None => Err("invalid token".into()),
+        }
+    }
+
+    // Invalidates every token issued so far
+    async fn rotate_key(&mut self, username: &str) -> Result<HS256Key, Box<dyn std::error::Error>> {
+        let key = HS256Key::generate();
+        let _: () = self
+            .con
+            .set(username.to_owned() + "_key", key.to_bytes())
+            .await?;
+        Ok(key)
+    }
+
+    async fn check_password(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ret: Option<String> = self.con.get(username.to_owned() + "_pass").await?;
+        match ret {
+            Some(stored)
+                if password::verify_password(password, &stored) != Verification::Invalid =>
+            {
+                Ok(())
+            }
+            _ => Err(INVALID_CREDENTIALS.into()),
+        }
+    }
+
+    // Without a password the caller has authenticated the user already
+    async fn check_identity(
+        &mut self,
+        username: &str,
+        password: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match password {
+            Some(password) => self.check_password(username, password).await,
+            None if self.user_exists(username).await? => Ok(()),
+            None => Err(INVALID_CREDENTIALS.into()),
+        }
+    }
+
+    // Deletes every key of the user, including the lookup entries of its API
+    // keys and OIDC identity
+    async fn purge_user(&mut self, username: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let api_key_ids: Vec<String> = self.con.smembers(username.to_owned() + "_apikeys").await?;
+        for id in api_key_ids {
+            let _: () = self.con.del("apikey:".to_owned() + &id + "_key").await?;
+        }
+        let subject: Option<String> = self.con.get(username.to_owned() + "_oidc").await?;
+        if let Some(subject) = subject {
+            let _: () = self
+                .con
+                .del("oidc:".to_owned() + &subject + "_user")
+                .await?;
+        }
+        // Revoked token entries are left to expire, the tokens no longer verify anyway
+        let _: () = self
+            .con
+            .del(&[
+                username.to_owned() + "_pass",
+                username.to_owned() + "_key",
+                username.to_owned() + "_roles",
+                username.to_owned() + "_reset",
+                username.to_owned() + "_totp",
+                username.to_owned() + "_totp_pending",
+                username.to_owned() + "_recovery",
+                username.to_owned() + "_oidc",
+                username.to_owned() + "_apikeys",
+                login_throttle::user_key(username) + "_failures",
+            ])
+            .await?;
+        let _: () = self.con.zrem(GUESTS, username).await?;
+        Ok(())
+    }
+
+    async fn get_roles(&mut self, username: &str) -> Result<Vec<Role>, Box<dyn std::error::Error>> {
+        let names: Vec<String> = self.con.smembers(username.to_owned() + "_roles").await?;
+        let mut roles = vec![Role::Player];
+        for name in names {
+            let role = Role::from_str(&name)?;
+            if !roles.contains(&role) {
+                roles.push(role);
+            }
+        }
+        roles.sort();
+        Ok(roles)
+    }
+
+    async fn user_exists(&mut self, username: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let exists: bool = self.con.exists(username.to_owned() + "_pass").await?;
+        Ok(exists)
+    }
+
+    // Collects the names of all users by scanning for `<username>_pass`
+    // keys, skipping `apikey:`/`oidc:` lookup entries which share the suffix
+    async fn usernames(&mut self) -> RedisResult<Vec<String>> {
+        let mut usernames = Vec::new();
+        let mut keys: redis::AsyncIter<String> = self.con.scan_match("*_pass").await?;
+        while let Some(key) = keys.next_item().await {
+            if let Some(username) = key.strip_suffix("_pass") {
+                if !username.contains(['_', ':']) {
+                    usernames.push(username.to_string());
+                }
+            }
+        }
+        usernames.sort();
+        usernames.dedup();
+        Ok(usernames)
+    }
+
+    // Hashes every `<username>_pass` entry still stored as plaintext.
+    // Accounts not migrated this way are rehashed on their next login anyway.
+    pub async fn migrate_passwords(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut migrated = 0;
+        let usernames = self.usernames().await?;
+        for username in usernames {
+            let key = username + "_pass";
+            let stored: String = self.con.get(&key).await?;
+            if password::is_hashed(&stored) {
+                continue;
+            }
+            let hash = password::hash_password(&stored)?;
+            let _: () = self.con.set(&key, hash).await?;
+            migrated += 1;
+        }
+        info!("Migrated {} plaintext passwords", migrated);
+        Ok(migrated)
     }
 }
 
 // Two entries are created:
-// <username>_pass - <password>
+// <username>_pass - <argon2id PHC string>
 // <username>_key - <key>
+// Roles other than player are granted by adding them to the set:
+// <username>_roles - {teacher, admin}
+// Failed logins are counted per `user:<username>` and `ip:<address>` in:
+// <key>_failures - {failures, last_failure}
+// A pending admin-issued password reset expires with:
+// <username>_reset - <argon2id PHC string of the reset token>
+// With 2FA enabled, the TOTP secret, the recovery code hashes and,
+// until confirmed, the secret of a pending enrollment are stored as:
+// <username>_totp - {secret, last_step}
+// <username>_recovery - {<SHA-256 hash>, ...}
+// <username>_totp_pending - <secret>
+// Users created by an OIDC login are linked to the provider subject with:
+// <username>_oidc - <issuer>|<sub>
+// oidc:<issuer>|<sub>_user - <username>
+// API keys are listed per user and stored by their id:
+// <username>_apikeys - {<id>, ...}
+// apikey:<id>_key - {username, hash, info}, info being the JSON `ApiKeyInfo`
+// Guests are created with the same entries, expiring with the guest, and
+// are listed with their expiry so that their instances can be cleaned up:
+// lynx:guests - sorted set of <username> by expiry as unix timestamp
+// Revoked tokens are kept until they expire as:
+// <username>_revoked_<jti> - 1
+const GUESTS: &str = "lynx:guests";
 
 #[async_trait]
 impl AuthManager for RedisAuthManager {
@@ -33,18 +226,16 @@ impl AuthManager for RedisAuthManager {
         username: String,
         password: String,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        if username.contains("_") {
+        if username.contains('_') {
             return Err("Username contains illegal character: _".into());
         }
 
-        let ret: RedisResult<String> = self.con.get(username.clone() + "_pass").await;
-        if let Ok(_) = ret {
-            return Err("User already exists".into());
-        }
-
-        let ret: Result<(), RedisError> = self.con.set(username.clone() + "_pass", password).await;
-        if let Err(e) = ret {
-            return Err(("Error creating user: ".to_owned() + &e.to_string()).into());
+        let hash = password::hash_password(&password)?;
+        let ret: Result<bool, RedisError> = self.con.set_nx(username.clone() + "_pass", hash).await;
+        match ret {
+            Ok(true) => (),
+            Ok(false) => return Err("User already exists".into()),
+            Err(e) => return Err(("Error creating user: ".to_owned() + &e.to_string()).into()),
         }
 
         let key = HS256Key::generate();
@@ -52,40 +243,714 @@ impl AuthManager for RedisAuthManager {
             .con
             .set(username.clone() + "_key", key.to_bytes())
             .await;
-        let claims = Claims::create(Duration::from_hours(2));
-        let token = key.authenticate(claims)?;
-        Ok(token)
+        token::create_token(&key, &self.token_config, &username, &[Role::Player])
     }
     async fn login(
         &mut self,
         username: String,
         password: String,
-    ) -> Result<String, Box<dyn std::error::Error>> {
+    ) -> Result<LoginOutcome, Box<dyn std::error::Error>> {
         let ret: Result<String, RedisError> = self.con.get(username.clone() + "_pass").await;
-        if let Err(_e) = ret {
-            return Err("User does not exist".into());
+        let stored = match ret {
+            Ok(stored) => stored,
+            Err(_) => {
+                password::dummy_verify(&password);
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        };
+        match password::verify_password(&password, &stored) {
+            Verification::Valid => (),
+            Verification::ValidNeedsRehash => {
+                let hash = password::hash_password(&password)?;
+                let ret: Result<(), RedisError> =
+                    self.con.set(username.clone() + "_pass", hash).await;
+                if let Err(e) = ret {
+                    error!("Rehashing password failed at: {}", e);
+                }
+            }
+            Verification::Invalid => return Err(INVALID_CREDENTIALS.into()),
+        }
+        let key = self.get_key(&username).await?;
+        let two_factor: bool = self.con.exists(username.clone() + "_totp").await?;
+        if two_factor {
+            let token = token::create_two_factor_token(&key, &self.token_config, &username)?;
+            return Ok(LoginOutcome::TwoFactorRequired(token));
+        }
+        let roles = self.get_roles(&username).await?;
+        let token = token::create_token(&key, &self.token_config, &username, &roles)?;
+        Ok(LoginOutcome::Token(token))
+    }
+
+    async fn verify_second_factor(
+        &mut self,
+        username: String,
+        two_factor_token: String,
+        code: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let key = self.get_key(&username).await?;
+        let claims =
+            token::verify_two_factor_token(&key, &self.token_config, &username, &two_factor_token)?;
+        if self.is_revoked(&username, &claims).await {
+            return Err("invalid token".into());
         }
-        let pass = ret.unwrap();
-        if pass != password {
-            return Err("Wrong password".into());
+        let (secret, last_step): (Option<String>, Option<u64>) = self
+            .con
+            .hget(username.clone() + "_totp", &["secret", "last_step"])
+            .await?;
+        let secret = match secret {
+            Some(secret) => secret,
+            None => return Err("2FA is not enabled".into()),
+        };
+        if let Some(step) = two_factor::verify_code(&secret, &code, last_step.unwrap_or(0)) {
+            let _: () = self
+                .con
+                .hset(username.clone() + "_totp", "last_step", step)
+                .await?;
+        } else {
+            let hashes: Vec<String> = self.con.smembers(username.clone() + "_recovery").await?;
+            let i = match two_factor::find_recovery_code(&code, &hashes) {
+                Some(i) => i,
+                None => return Err("Invalid code".into()),
+            };
+            // Removing first makes sure the recovery code is used only once
+            let removed: u32 = self
+                .con
+                .srem(username.clone() + "_recovery", &hashes[i])
+                .await?;
+            if removed == 0 {
+                return Err("Invalid code".into());
+            }
         }
-        let ret: Vec<u8> = self.con.get(username.clone() + "_key").await.unwrap();
-        let key = HS256Key::from_bytes(&ret);
-        let claims = Claims::create(Duration::from_hours(2));
-        let token = key.authenticate(claims)?;
-        Ok(token)
+        // The challenge can only be completed once
+        self.revoke(&username, &claims).await?;
+        let roles = self.get_roles(&username).await?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn create_guest(
+        &mut self,
+        lifetime: u64,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        let username = GUEST_PREFIX.to_owned() + &token::generate_id()[0..8];
+        // Nobody knows this password, guests only have their token
+        let hash = password::hash_password(&token::generate_id())?;
+        let created: bool = self.con.set_nx(username.clone() + "_pass", hash).await?;
+        if !created {
+            return Err("User already exists".into());
+        }
+
+        let key = HS256Key::generate();
+        let expires_at = token::now_secs() + lifetime;
+        // Redis does not accept a TTL of 0
+        let ttl = lifetime.max(1) as usize;
+        let _: () = redis::pipe()
+            .atomic()
+            .expire(username.clone() + "_pass", ttl)
+            .ignore()
+            .set_ex(username.clone() + "_key", key.to_bytes(), ttl)
+            .ignore()
+            .sadd(username.clone() + "_roles", Role::Guest.to_string())
+            .ignore()
+            .expire(username.clone() + "_roles", ttl)
+            .ignore()
+            .zadd(GUESTS, &username, expires_at)
+            .ignore()
+            .query_async(&mut self.con)
+            .await?;
+        let roles = self.get_roles(&username).await?;
+        let token =
+            token::create_guest_token(&key, &self.token_config, &username, &roles, expires_at)?;
+        Ok((username, token))
+    }
+
+    async fn count_guests(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let count: usize = self
+            .con
+            .zcount(GUESTS, token::now_secs() + 1, "+inf")
+            .await?;
+        Ok(count)
+    }
+
+    async fn remove_expired_guests(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let expired: Vec<String> = self.con.zrangebyscore(GUESTS, 0, token::now_secs()).await?;
+        for username in &expired {
+            self.purge_user(username).await?;
+        }
+        Ok(expired)
+    }
+
+    async fn upgrade_guest(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let expires_at: Option<u64> = self.con.zscore(GUESTS, &username).await?;
+        match expires_at {
+            Some(expires_at) if expires_at > token::now_secs() => (),
+            _ => return Err("Not a guest".into()),
+        }
+        let hash = password::hash_password(&password)?;
+        // SET drops the TTL of `_pass`, `rotate_key` the one of `_key`
+        let _: () = redis::pipe()
+            .atomic()
+            .set(username.clone() + "_pass", hash)
+            .ignore()
+            .srem(username.clone() + "_roles", Role::Guest.to_string())
+            .ignore()
+            .persist(username.clone() + "_roles")
+            .ignore()
+            .zrem(GUESTS, &username)
+            .ignore()
+            .query_async(&mut self.con)
+            .await?;
+        let key = self.rotate_key(&username).await?;
+        let roles = self.get_roles(&username).await?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn find_linked_user(
+        &mut self,
+        subject: String,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let username: Option<String> = self
+            .con
+            .get("oidc:".to_owned() + &subject + "_user")
+            .await?;
+        Ok(username)
+    }
+
+    async fn external_login(
+        &mut self,
+        subject: String,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let linked = self.find_linked_user(subject.clone()).await?;
+        match linked {
+            Some(linked) if linked == username => {
+                let key = self.get_key(&username).await?;
+                let roles = self.get_roles(&username).await?;
+                return token::create_token(&key, &self.token_config, &username, &roles);
+            }
+            Some(_) => return Err("Identity is linked to another user".into()),
+            None => (),
+        }
+        if username.contains('_') {
+            return Err("Username contains illegal character: _".into());
+        }
+
+        // Nobody knows this password, the user logs in through the provider
+        let hash = password::hash_password(&token::generate_id())?;
+        let created: bool = self.con.set_nx(username.clone() + "_pass", hash).await?;
+        if !created {
+            return Err("User already exists".into());
+        }
+        let key = HS256Key::generate();
+        let _: () = redis::pipe()
+            .atomic()
+            .set(username.clone() + "_key", key.to_bytes())
+            .ignore()
+            .set(username.clone() + "_oidc", &subject)
+            .ignore()
+            .set("oidc:".to_owned() + &subject + "_user", &username)
+            .ignore()
+            .query_async(&mut self.con)
+            .await?;
+        token::create_token(&key, &self.token_config, &username, &[Role::Player])
     }
 
     async fn validate_token(
         &mut self,
         username: String,
         token: String,
+    ) -> Result<TokenClaims, Box<dyn std::error::Error>> {
+        let key = self.get_key(&username).await?;
+        let claims = token::verify_token(&key, &self.token_config, &username, &token)?;
+
+        if self.is_revoked(&username, &claims).await {
+            return Err("invalid token".into());
+        }
+        Ok(claims.custom)
+    }
+
+    async fn revoke_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let key = self.get_key(&username).await?;
+        let claims = token::verify_refreshable_token(&key, &self.token_config, &username, &token)?;
+        self.revoke(&username, &claims).await
+    }
+
+    async fn refresh_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let key = self.get_key(&username).await?;
+        let claims = token::verify_refreshable_token(&key, &self.token_config, &username, &token)?;
+        if self.is_revoked(&username, &claims).await {
+            return Err("invalid token".into());
+        }
+
+        let roles = self.get_roles(&username).await?;
+        let guest_expires_at: Option<u64> = self.con.zscore(GUESTS, &username).await?;
+        let new_token = match guest_expires_at {
+            Some(expires_at) => {
+                token::create_guest_token(&key, &self.token_config, &username, &roles, expires_at)?
+            }
+            None => token::refresh_token(&key, &self.token_config, &username, &roles, &claims)?,
+        };
+        self.revoke(&username, &claims).await?;
+        Ok(new_token)
+    }
+
+    async fn revoke_all_tokens(
+        &mut self,
+        username: String,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let ret: Vec<u8> = self.con.get(username + "_key").await.unwrap();
-        let key = HS256Key::from_bytes(&ret);
-        match key.verify_token::<NoCustomClaims>(&token, None) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("invalid token".into()),
+        if !self.user_exists(&username).await? {
+            return Err("User does not exist".into());
         }
+        self.rotate_key(&username).await?;
+        Ok(())
+    }
+
+    async fn change_password(
+        &mut self,
+        username: String,
+        current_password: Option<String>,
+        new_password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.check_identity(&username, current_password.as_deref())
+            .await?;
+        let hash = password::hash_password(&new_password)?;
+        let _: () = self.con.set(username.clone() + "_pass", hash).await?;
+        let _: () = self.con.del(username.clone() + "_reset").await?;
+        let key = self.rotate_key(&username).await?;
+        let roles = self.get_roles(&username).await?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn create_password_reset(
+        &mut self,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        if !self.user_exists(&username).await? {
+            return Err("User does not exist".into());
+        }
+        let reset_token = token::generate_id();
+        let hash = password::hash_password(&reset_token)?;
+        let _: () = self
+            .con
+            .set_ex(username + "_reset", hash, PASSWORD_RESET_TTL as usize)
+            .await?;
+        Ok(reset_token)
+    }
+
+    async fn reset_password(
+        &mut self,
+        username: String,
+        reset_token: String,
+        new_password: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ret: Option<String> = self.con.get(username.clone() + "_reset").await?;
+        match ret {
+            Some(hash)
+                if password::verify_password(&reset_token, &hash) != Verification::Invalid => {}
+            _ => return Err("Invalid reset token".into()),
+        }
+        // Deleting first makes sure the reset token is used only once
+        let deleted: u32 = self.con.del(username.clone() + "_reset").await?;
+        if deleted == 0 {
+            return Err("Invalid reset token".into());
+        }
+        let hash = password::hash_password(&new_password)?;
+        let _: () = self.con.set(username.clone() + "_pass", hash).await?;
+        self.rotate_key(&username).await?;
+        Ok(())
+    }
+
+    async fn delete_user(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_identity(&username, password.as_deref()).await?;
+        self.purge_user(&username).await
+    }
+
+    async fn create_api_key(
+        &mut self,
+        username: String,
+        name: String,
+        scopes: Vec<ApiKeyScope>,
+    ) -> Result<(ApiKeyInfo, String), Box<dyn std::error::Error>> {
+        api_key::validate_name(&name)?;
+        if !self.user_exists(&username).await? {
+            return Err("User does not exist".into());
+        }
+        let count: usize = self.con.scard(username.clone() + "_apikeys").await?;
+        if count >= api_key::MAX_API_KEYS {
+            return Err("Too many API keys".into());
+        }
+        let info = ApiKeyInfo {
+            id: token::generate_id(),
+            name,
+            scopes,
+            created_at: token::now_secs(),
+        };
+        let (key, hash) = api_key::generate(&info.id);
+        let _: () = redis::pipe()
+            .atomic()
+            .hset_multiple(
+                "apikey:".to_owned() + &info.id + "_key",
+                &[
+                    ("username", username.clone()),
+                    ("hash", hash),
+                    ("info", serde_json::to_string(&info)?),
+                ],
+            )
+            .ignore()
+            .sadd(username + "_apikeys", &info.id)
+            .ignore()
+            .query_async(&mut self.con)
+            .await?;
+        Ok((info, key))
+    }
+
+    async fn list_api_keys(
+        &mut self,
+        username: String,
+    ) -> Result<Vec<ApiKeyInfo>, Box<dyn std::error::Error>> {
+        let ids: Vec<String> = self.con.smembers(username + "_apikeys").await?;
+        let mut keys = Vec::new();
+        for id in ids {
+            let info: Option<String> = self
+                .con
+                .hget("apikey:".to_owned() + &id + "_key", "info")
+                .await?;
+            if let Some(info) = info {
+                keys.push(serde_json::from_str::<ApiKeyInfo>(&info)?);
+            }
+        }
+        keys.sort_by_key(|key| key.created_at);
+        Ok(keys)
+    }
+
+    async fn revoke_api_key(
+        &mut self,
+        username: String,
+        id: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let removed: u32 = self.con.srem(username + "_apikeys", &id).await?;
+        if removed == 0 {
+            return Err("API key does not exist".into());
+        }
+        let _: () = self.con.del("apikey:".to_owned() + &id + "_key").await?;
+        Ok(())
+    }
+
+    async fn validate_api_key(
+        &mut self,
+        key: String,
+    ) -> Result<(UserInfo, Vec<ApiKeyScope>), Box<dyn std::error::Error>> {
+        let (id, secret) = match api_key::parse(&key) {
+            Some(parsed) => parsed,
+            None => return Err("invalid API key".into()),
+        };
+        let (username, hash, info): (Option<String>, Option<String>, Option<String>) = self
+            .con
+            .hget(
+                "apikey:".to_owned() + id + "_key",
+                &["username", "hash", "info"],
+            )
+            .await?;
+        match (username, hash, info) {
+            (Some(username), Some(hash), Some(info)) if api_key::verify_secret(secret, &hash) => {
+                let info: ApiKeyInfo = serde_json::from_str(&info)?;
+                let roles = self.get_roles(&username).await?;
+                Ok((UserInfo { username, roles }, info.scopes))
+            }
+            _ => Err("invalid API key".into()),
+        }
+    }
+
+    async fn begin_totp_enrollment(
+        &mut self,
+        username: String,
+    ) -> Result<TotpEnrollment, Box<dyn std::error::Error>> {
+        if !self.user_exists(&username).await? {
+            return Err("User does not exist".into());
+        }
+        let enabled: bool = self.con.exists(username.clone() + "_totp").await?;
+        if enabled {
+            return Err("2FA is already enabled".into());
+        }
+        let secret = two_factor::generate_secret();
+        let enrollment = two_factor::enrollment(&secret, &self.token_config.issuer, &username)?;
+        let _: () = self
+            .con
+            .set_ex(
+                username + "_totp_pending",
+                secret,
+                two_factor::ENROLLMENT_TTL as usize,
+            )
+            .await?;
+        Ok(enrollment)
+    }
+
+    async fn confirm_totp_enrollment(
+        &mut self,
+        username: String,
+        code: String,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let ret: Option<String> = self.con.get(username.clone() + "_totp_pending").await?;
+        let secret = match ret {
+            Some(secret) => secret,
+            None => return Err("No pending 2FA enrollment".into()),
+        };
+        let last_step = match two_factor::verify_code(&secret, &code, 0) {
+            Some(step) => step,
+            None => return Err("Invalid code".into()),
+        };
+        let codes = two_factor::generate_recovery_codes();
+        let hashes: Vec<String> = codes
+            .iter()
+            .map(|code| two_factor::hash_recovery_code(code))
+            .collect();
+        let _: () = redis::pipe()
+            .atomic()
+            .del(&[
+                username.clone() + "_recovery",
+                username.clone() + "_totp_pending",
+            ])
+            .ignore()
+            .sadd(username.clone() + "_recovery", hashes)
+            .ignore()
+            .hset_multiple(
+                username.clone() + "_totp",
+                &[("secret", secret), ("last_step", last_step.to_string())],
+            )
+            .ignore()
+            .query_async(&mut self.con)
+            .await?;
+        Ok(codes)
+    }
+
+    async fn disable_totp(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_identity(&username, password.as_deref()).await?;
+        let _: () = self
+            .con
+            .del(&[
+                username.clone() + "_totp",
+                username.clone() + "_totp_pending",
+                username.clone() + "_recovery",
+            ])
+            .await?;
+        Ok(())
+    }
+
+    async fn list_users(&mut self) -> Result<Vec<UserInfo>, Box<dyn std::error::Error>> {
+        let mut users = Vec::new();
+        let usernames = self.usernames().await?;
+        for username in usernames {
+            let roles = self.get_roles(&username).await?;
+            users.push(UserInfo { username, roles });
+        }
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    async fn grant_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.user_exists(&username).await? {
+            return Err("User does not exist".into());
+        }
+        let _: () = self.con.sadd(username + "_roles", role.to_string()).await?;
+        Ok(())
+    }
+
+    async fn revoke_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.user_exists(&username).await? {
+            return Err("User does not exist".into());
+        }
+        let _: () = self
+            .con
+            .srem(username.clone() + "_roles", role.to_string())
+            .await?;
+        self.revoke_all_tokens(username).await
+    }
+
+    async fn get_login_attempts(
+        &mut self,
+        key: String,
+    ) -> Result<LoginAttempts, Box<dyn std::error::Error>> {
+        let (failures, last_failure): (Option<u32>, Option<u64>) = self
+            .con
+            .hget(key + "_failures", &["failures", "last_failure"])
+            .await?;
+        Ok(LoginAttempts {
+            failures: failures.unwrap_or(0),
+            last_failure: last_failure.unwrap_or(0),
+        })
+    }
+
+    async fn record_failed_login(
+        &mut self,
+        key: String,
+        expire_after: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let key = key + "_failures";
+        let _: () = redis::pipe()
+            .atomic()
+            .hincr(&key, "failures", 1)
+            .ignore()
+            .hset(&key, "last_failure", token::now_secs())
+            .ignore()
+            .expire(&key, expire_after as usize)
+            .ignore()
+            .query_async(&mut self.con)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_failed_logins(&mut self, key: String) -> Result<(), Box<dyn std::error::Error>> {
+        let _: () = self.con.del(key + "_failures").await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::env;
+
+    use super::*;
+
+    const USERNAME: &str = "lynx-test-alice";
+
+    async fn get_auth_manager() -> RedisAuthManager {
+        let password = match env::var("REDIS_PASSWORD") {
+            Ok(v) => v,
+            Err(_) => panic!("$REDIS_PASSWORD is not set!"),
+        };
+
+        let url = "redis://default:".to_string() + &password + "@127.0.0.1:6379";
+        let mut auth = RedisAuthManager::new(url, TokenConfig::default()).await;
+        let _: () = auth
+            .con
+            .del(&[USERNAME.to_owned() + "_pass", USERNAME.to_owned() + "_key"])
+            .await
+            .unwrap();
+        auth
+    }
+
+    #[tokio::test]
+    #[ignore]
+    #[serial]
+    async fn test_register_and_login() {
+        let mut auth = get_auth_manager().await;
+        let token = auth
+            .register(USERNAME.to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token(USERNAME.to_string(), token)
+            .await
+            .is_ok());
+        assert!(auth
+            .login(USERNAME.to_string(), "hunter2".to_string())
+            .await
+            .is_ok());
+        assert!(auth
+            .login(USERNAME.to_string(), "hunter3".to_string())
+            .await
+            .is_err());
+        auth.delete_user(USERNAME.to_string(), Some("hunter2".to_string()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    #[serial]
+    async fn test_register_existing() {
+        let mut auth = get_auth_manager().await;
+        auth.register(USERNAME.to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .register(USERNAME.to_string(), "other".to_string())
+            .await
+            .is_err());
+        assert!(auth
+            .login(USERNAME.to_string(), "hunter2".to_string())
+            .await
+            .is_ok());
+        auth.delete_user(USERNAME.to_string(), Some("hunter2".to_string()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    #[serial]
+    async fn test_list_users() {
+        let mut auth = get_auth_manager().await;
+        auth.register(USERNAME.to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        // Lookup entries sharing the suffix are not users
+        let _: () = auth
+            .con
+            .set("oidc:lynx-test|x_pass", USERNAME)
+            .await
+            .unwrap();
+
+        let users = auth.list_users().await.unwrap();
+        assert!(users.iter().any(|user| user.username == USERNAME));
+        assert!(users.iter().all(|user| !user.username.contains(':')));
+
+        let _: () = auth.con.del("oidc:lynx-test|x_pass").await.unwrap();
+        auth.delete_user(USERNAME.to_string(), Some("hunter2".to_string()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    #[serial]
+    async fn test_expired_guests() {
+        let mut auth = get_auth_manager().await;
+        let (username, _) = auth.create_guest(1).await.unwrap();
+        let (info, _) = auth
+            .create_api_key(username.clone(), "ci".to_string(), vec![ApiKeyScope::Proxy])
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+        let expired = auth.remove_expired_guests().await.unwrap();
+        assert!(expired.contains(&username));
+        // Keys without a TTL are deleted with the guest as well
+        let lookup: bool = auth
+            .con
+            .exists("apikey:".to_owned() + &info.id + "_key")
+            .await
+            .unwrap();
+        assert!(!lookup);
+        let api_keys: bool = auth.con.exists(username + "_apikeys").await.unwrap();
+        assert!(!api_keys);
     }
 }
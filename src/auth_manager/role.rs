@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Every user is a player, teachers and admins are granted explicitly.
+// Guests are players without account, see `AuthManager::create_guest`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    Teacher,
+    Admin,
+    Guest,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::Player => "player",
+            Role::Teacher => "teacher",
+            Role::Admin => "admin",
+            Role::Guest => "guest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Role, String> {
+        match s {
+            "player" => Ok(Role::Player),
+            "teacher" => Ok(Role::Teacher),
+            "admin" => Ok(Role::Admin),
+            "guest" => Ok(Role::Guest),
+            _ => Err("Unknown role: ".to_owned() + s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for role in [Role::Player, Role::Teacher, Role::Admin, Role::Guest] {
+            assert_eq!(Role::from_str(&role.to_string()), Ok(role));
+            let json = serde_json::to_string(&role).unwrap();
+            assert_eq!(json, format!("\"{}\"", role));
+        }
+        assert!(Role::from_str("root").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::auth_manager::token::TokenClaims;
+
+use jwt_simple::prelude::*;
+use jwt_simple::JWTError;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Asymmetric keys tokens are signed with instead of the per-user HS256 keys,
+// so that other services can verify tokens with the keys served as JWKS.
+// The key ID of a key is the stem of its PEM file, e.g. `2024-06.pem` -> `2024-06`.
+//
+// To rotate, a new key is published as verification key first, so that
+// downstream services have it cached before it signs anything. Once it
+// is the signing key, the previous one stays a verification key until the
+// last token signed with it can no longer be refreshed.
+
+// There is only ever one of these, its size does not matter
+#[allow(clippy::large_enum_variant)]
+enum KeyPair {
+    ES256(ES256KeyPair),
+    RS256(RS256KeyPair),
+}
+
+enum PublicKey {
+    ES256(ES256PublicKey),
+    RS256(RS256PublicKey),
+}
+
+pub struct SigningKeys {
+    signing_key: KeyPair,
+    // Includes the public key of `signing_key`
+    verification_keys: Vec<(String, PublicKey)>,
+}
+
+impl fmt::Debug for SigningKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key_ids: Vec<&String> = self.verification_keys.iter().map(|(kid, _)| kid).collect();
+        f.debug_struct("SigningKeys")
+            .field("key_ids", &key_ids)
+            .finish()
+    }
+}
+
+impl SigningKeys {
+    // `verification_paths` may contain private or public keys
+    pub fn load(
+        signing_path: &str,
+        verification_paths: &[String],
+    ) -> Result<SigningKeys, Box<dyn std::error::Error>> {
+        let kid = key_id(signing_path)?;
+        let (signing_key, public_key) = match parse(&kid, &fs::read_to_string(signing_path)?)? {
+            (Some(key_pair), public_key) => (key_pair, public_key),
+            (None, _) => return Err(format!("{} is not a private key", signing_path).into()),
+        };
+        let mut keys = SigningKeys {
+            signing_key,
+            verification_keys: vec![(kid, public_key)],
+        };
+        for path in verification_paths {
+            let kid = key_id(path)?;
+            let (_, public_key) = parse(&kid, &fs::read_to_string(path)?)?;
+            keys.add_verification_key(kid, public_key)?;
+        }
+        Ok(keys)
+    }
+
+    fn add_verification_key(
+        &mut self,
+        kid: String,
+        public_key: PublicKey,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self
+            .verification_keys
+            .iter()
+            .any(|(other, _)| *other == kid)
+        {
+            return Err(format!("Duplicate key ID: {}", kid).into());
+        }
+        self.verification_keys.push((kid, public_key));
+        Ok(())
+    }
+
+    pub fn sign(&self, claims: JWTClaims<TokenClaims>) -> Result<String, jwt_simple::Error> {
+        match &self.signing_key {
+            KeyPair::ES256(key_pair) => key_pair.sign(claims),
+            KeyPair::RS256(key_pair) => key_pair.sign(claims),
+        }
+    }
+
+    // Picks the verification key by the `kid` header of the token
+    pub fn verify(
+        &self,
+        token: &str,
+        options: VerificationOptions,
+    ) -> Result<JWTClaims<TokenClaims>, jwt_simple::Error> {
+        let metadata = Token::decode_metadata(token)?;
+        let public_key = self
+            .verification_keys
+            .iter()
+            .find(|(kid, _)| Some(kid.as_str()) == metadata.key_id())
+            .map(|(_, public_key)| public_key);
+        match public_key {
+            Some(PublicKey::ES256(key)) => key.verify_token(token, Some(options)),
+            Some(PublicKey::RS256(key)) => key.verify_token(token, Some(options)),
+            None => Err(JWTError::KeyIdentifierMismatch.into()),
+        }
+    }
+
+    // JSON Web Key Set as in RFC 7517
+    pub fn jwks(&self) -> serde_json::Value {
+        let keys: Vec<serde_json::Value> = self
+            .verification_keys
+            .iter()
+            .map(|(kid, public_key)| match public_key {
+                PublicKey::ES256(key) => {
+                    // 0x04 || x || y
+                    let point = key.public_key().to_bytes_uncompressed();
+                    serde_json::json!({
+                        "kty": "EC",
+                        "crv": "P-256",
+                        "x": base64url(&point[1..33]),
+                        "y": base64url(&point[33..65]),
+                        "kid": kid,
+                        "alg": "ES256",
+                        "use": "sig",
+                    })
+                }
+                PublicKey::RS256(key) => {
+                    let components = key.to_components();
+                    serde_json::json!({
+                        "kty": "RSA",
+                        "n": base64url(&components.n),
+                        "e": base64url(&components.e),
+                        "kid": kid,
+                        "alg": "RS256",
+                        "use": "sig",
+                    })
+                }
+            })
+            .collect();
+        serde_json::json!({ "keys": keys })
+    }
+}
+
+fn key_id(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    match Path::new(path).file_stem() {
+        Some(stem) => Ok(stem.to_string_lossy().to_string()),
+        None => Err(format!("Cannot derive key ID from {}", path).into()),
+    }
+}
+
+// Returns the key pair if `pem` is a private key and its public key in any case
+fn parse(kid: &str, pem: &str) -> Result<(Option<KeyPair>, PublicKey), Box<dyn std::error::Error>> {
+    if let Ok(key_pair) = ES256KeyPair::from_pem(pem) {
+        let key_pair = key_pair.with_key_id(kid);
+        let public_key = PublicKey::ES256(key_pair.public_key());
+        return Ok((Some(KeyPair::ES256(key_pair)), public_key));
+    }
+    if let Ok(key_pair) = RS256KeyPair::from_pem(pem) {
+        let key_pair = key_pair.with_key_id(kid);
+        let public_key = PublicKey::RS256(key_pair.public_key());
+        return Ok((Some(KeyPair::RS256(key_pair)), public_key));
+    }
+    if let Ok(public_key) = ES256PublicKey::from_pem(pem) {
+        return Ok((None, PublicKey::ES256(public_key.with_key_id(kid))));
+    }
+    if let Ok(public_key) = RS256PublicKey::from_pem(pem) {
+        return Ok((None, PublicKey::RS256(public_key.with_key_id(kid))));
+    }
+    Err(format!("Key {} is neither an ES256 nor an RS256 key", kid).into())
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    Base64UrlSafeNoPadding::encode_to_string(bytes).unwrap()
+}
+
+#[cfg(test)]
+impl SigningKeys {
+    pub fn generate(kid: &str) -> SigningKeys {
+        let key_pair = ES256KeyPair::generate().with_key_id(kid);
+        let public_key = PublicKey::ES256(key_pair.public_key());
+        SigningKeys {
+            signing_key: KeyPair::ES256(key_pair),
+            verification_keys: vec![(kid.to_string(), public_key)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn claims() -> JWTClaims<TokenClaims> {
+        let custom = TokenClaims {
+            scope: "session".to_string(),
+            auth_time: 0,
+            roles: Vec::new(),
+            generation: None,
+        };
+        Claims::with_custom_claims(custom, Duration::from_mins(5))
+    }
+
+    fn write_key(name: &str, pem: &str) -> String {
+        let path = env::temp_dir()
+            .join(format!("lynx-{}-{}.pem", name, std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        fs::write(&path, pem).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_es256() {
+        let path = write_key("es256", &ES256KeyPair::generate().to_pem().unwrap());
+        let keys = SigningKeys::load(&path, &[]).unwrap();
+        let token = keys.sign(claims()).unwrap();
+        let metadata = Token::decode_metadata(&token).unwrap();
+        assert_eq!(metadata.algorithm(), "ES256");
+        assert_eq!(metadata.key_id(), Some(key_id(&path).unwrap().as_str()));
+        assert!(keys.verify(&token, VerificationOptions::default()).is_ok());
+
+        let jwks = keys.jwks();
+        assert_eq!(jwks["keys"][0]["kty"], "EC");
+        assert_eq!(jwks["keys"][0]["kid"], key_id(&path).unwrap());
+        assert_eq!(jwks["keys"][0]["x"].as_str().unwrap().len(), 43);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rs256() {
+        let key_pair = RS256KeyPair::generate(2048).unwrap();
+        let path = write_key("rs256", &key_pair.to_pem().unwrap());
+        let keys = SigningKeys::load(&path, &[]).unwrap();
+        let token = keys.sign(claims()).unwrap();
+        assert_eq!(Token::decode_metadata(&token).unwrap().algorithm(), "RS256");
+        assert!(keys.verify(&token, VerificationOptions::default()).is_ok());
+        assert_eq!(keys.jwks()["keys"][0]["kty"], "RSA");
+        assert_eq!(keys.jwks()["keys"][0]["e"], "AQAB");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old_key = ES256KeyPair::generate();
+        let old_path = write_key("old", &old_key.to_pem().unwrap());
+        let new_path = write_key("new", &ES256KeyPair::generate().to_pem().unwrap());
+        // Only the public part of the next key is known yet
+        let next_path = write_key(
+            "next",
+            &ES256KeyPair::generate().public_key().to_pem().unwrap(),
+        );
+
+        let old_keys = SigningKeys::load(&old_path, &[]).unwrap();
+        let token = old_keys.sign(claims()).unwrap();
+
+        let new_keys =
+            SigningKeys::load(&new_path, &[old_path.clone(), next_path.clone()]).unwrap();
+        assert_eq!(new_keys.jwks()["keys"].as_array().unwrap().len(), 3);
+        assert!(new_keys
+            .verify(&token, VerificationOptions::default())
+            .is_ok());
+        let new_token = new_keys.sign(claims()).unwrap();
+        assert!(old_keys
+            .verify(&new_token, VerificationOptions::default())
+            .is_err());
+
+        // The old key is retired
+        let newer_keys = SigningKeys::load(&new_path, std::slice::from_ref(&next_path)).unwrap();
+        assert!(newer_keys
+            .verify(&token, VerificationOptions::default())
+            .is_err());
+        assert!(SigningKeys::load(&next_path, &[]).is_err());
+
+        for path in [old_path, new_path, next_path] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
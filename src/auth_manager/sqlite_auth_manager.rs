@@ -0,0 +1,1357 @@
+use crate::auth_manager::api_key::{self, ApiKeyInfo, ApiKeyScope};
+use crate::auth_manager::login_throttle::LoginAttempts;
+use crate::auth_manager::password::{self, Verification};
+use crate::auth_manager::role::Role;
+use crate::auth_manager::token::{self, TokenClaims, TokenConfig};
+use crate::auth_manager::two_factor::{self, TotpEnrollment};
+use crate::auth_manager::{
+    login_throttle, AuthManager, LoginOutcome, UserInfo, GUEST_PREFIX, INVALID_CREDENTIALS,
+    PASSWORD_RESET_TTL,
+};
+
+use async_trait::async_trait;
+use jwt_simple::prelude::{HS256Key, JWTClaims};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::info;
+
+// Schema migrations, applied in order. The number of applied migrations is
+// kept in `PRAGMA user_version`. Never edit a released migration, add one.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE users (
+        username TEXT PRIMARY KEY NOT NULL,
+        password TEXT NOT NULL, -- argon2id PHC string
+        key BLOB NOT NULL,
+        -- Set for users created by an OIDC login
+        oidc_subject TEXT UNIQUE,
+        -- Unix timestamp after which a guest is deleted, NULL for regular users
+        guest_expires_at INTEGER
+    );
+    CREATE INDEX users_guest_expires_at ON users (guest_expires_at);
+
+    CREATE TABLE roles (
+        username TEXT NOT NULL REFERENCES users ON DELETE CASCADE,
+        role TEXT NOT NULL,
+        PRIMARY KEY (username, role)
+    );
+
+    CREATE TABLE revoked_tokens (
+        username TEXT NOT NULL REFERENCES users ON DELETE CASCADE,
+        jti TEXT NOT NULL,
+        keep_until INTEGER NOT NULL,
+        PRIMARY KEY (username, jti)
+    );
+
+    CREATE TABLE password_resets (
+        username TEXT PRIMARY KEY NOT NULL REFERENCES users ON DELETE CASCADE,
+        hash TEXT NOT NULL, -- argon2id PHC string of the reset token
+        expires_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE totp (
+        username TEXT PRIMARY KEY NOT NULL REFERENCES users ON DELETE CASCADE,
+        secret TEXT NOT NULL,
+        last_step INTEGER NOT NULL
+    );
+
+    CREATE TABLE pending_totp (
+        username TEXT PRIMARY KEY NOT NULL REFERENCES users ON DELETE CASCADE,
+        secret TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE recovery_codes (
+        id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL REFERENCES users ON DELETE CASCADE,
+        hash TEXT NOT NULL -- SHA-256 hash
+    );
+
+    CREATE TABLE api_keys (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL REFERENCES users ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        scopes TEXT NOT NULL, -- JSON array of `ApiKeyScope`
+        created_at INTEGER NOT NULL,
+        hash TEXT NOT NULL -- SHA-256 of the secret
+    );
+    CREATE INDEX api_keys_username ON api_keys (username);
+
+    -- Keyed by `login_throttle::user_key`/`ip_key`, not by user
+    CREATE TABLE login_failures (
+        key TEXT PRIMARY KEY NOT NULL,
+        failures INTEGER NOT NULL,
+        last_failure INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+"];
+
+struct User {
+    password: String,
+    key: Vec<u8>,
+    roles: Vec<Role>,
+    guest_expires_at: Option<u64>,
+}
+
+impl User {
+    // Expired guests are treated as gone before `remove_expired_guests` runs
+    fn is_expired_guest(&self) -> bool {
+        matches!(self.guest_expires_at, Some(expires_at) if expires_at <= token::now_secs())
+    }
+}
+
+fn migrate(con: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let version: usize = con.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "Auth database has schema version {}, this release only knows {}",
+            version,
+            MIGRATIONS.len()
+        )
+        .into());
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating auth database to schema version {}", i + 1);
+        let tx = con.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn is_constraint_violation(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation)
+}
+
+fn load_roles(con: &Connection, username: &str) -> Result<Vec<Role>, Box<dyn std::error::Error>> {
+    let mut statement = con.prepare("SELECT role FROM roles WHERE username = ?1")?;
+    let names = statement
+        .query_map([username], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    let mut roles = names
+        .iter()
+        .map(|name| Role::from_str(name))
+        .collect::<Result<Vec<Role>, _>>()?;
+    roles.sort();
+    Ok(roles)
+}
+
+fn load_user(con: &Connection, username: &str) -> Result<Option<User>, Box<dyn std::error::Error>> {
+    let user = con
+        .query_row(
+            "SELECT password, key, guest_expires_at FROM users WHERE username = ?1",
+            [username],
+            |row| {
+                Ok(User {
+                    password: row.get(0)?,
+                    key: row.get(1)?,
+                    roles: Vec::new(),
+                    guest_expires_at: row.get(2)?,
+                })
+            },
+        )
+        .optional()?;
+    match user {
+        Some(mut user) => {
+            user.roles = load_roles(con, username)?;
+            Ok(Some(user))
+        }
+        None => Ok(None),
+    }
+}
+
+fn user_exists(con: &Connection, username: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(con
+        .query_row(
+            "SELECT 1 FROM users WHERE username = ?1",
+            [username],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+// Inserts the user with its roles, failing if the name is taken
+fn insert_user(
+    con: &mut Connection,
+    username: &str,
+    password: &str,
+    key: &HS256Key,
+    roles: &[Role],
+    oidc_subject: Option<&str>,
+    guest_expires_at: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = con.transaction()?;
+    let inserted = tx.execute(
+        "INSERT INTO users (username, password, key, oidc_subject, guest_expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            username,
+            password,
+            key.to_bytes(),
+            oidc_subject,
+            guest_expires_at
+        ],
+    );
+    match inserted {
+        Err(e) if is_constraint_violation(&e) => return Err("User already exists".into()),
+        Err(e) => return Err(e.into()),
+        Ok(_) => (),
+    }
+    for role in roles {
+        tx.execute(
+            "INSERT INTO roles (username, role) VALUES (?1, ?2)",
+            params![username, role.to_string()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn is_revoked(
+    con: &Connection,
+    username: &str,
+    claims: &JWTClaims<TokenClaims>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let jti = claims.jwt_id.as_ref().unwrap();
+    Ok(con
+        .query_row(
+            "SELECT 1 FROM revoked_tokens WHERE username = ?1 AND jti = ?2",
+            params![username, jti],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+fn revoke(
+    con: &Connection,
+    username: &str,
+    claims: &JWTClaims<TokenClaims>,
+    config: &TokenConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = token::now_secs();
+    con.execute(
+        "DELETE FROM revoked_tokens WHERE username = ?1 AND keep_until <= ?2",
+        params![username, now],
+    )?;
+    con.execute(
+        "INSERT OR REPLACE INTO revoked_tokens (username, jti, keep_until) VALUES (?1, ?2, ?3)",
+        params![
+            username,
+            claims.jwt_id.as_ref().unwrap(),
+            now + token::denylist_ttl(claims, config)
+        ],
+    )?;
+    Ok(())
+}
+
+// Invalidates every token issued so far
+fn rotate_key(con: &Connection, username: &str) -> Result<HS256Key, Box<dyn std::error::Error>> {
+    let key = HS256Key::generate();
+    con.execute(
+        "UPDATE users SET key = ?2 WHERE username = ?1",
+        params![username, key.to_bytes()],
+    )?;
+    // Old tokens no longer verify, so their denylist entries are useless
+    con.execute("DELETE FROM revoked_tokens WHERE username = ?1", [username])?;
+    Ok(key)
+}
+
+fn set_password(
+    con: &Connection,
+    username: &str,
+    password: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    con.execute(
+        "UPDATE users SET password = ?2 WHERE username = ?1",
+        params![username, password::hash_password(password)?],
+    )?;
+    Ok(())
+}
+
+// Users in an embedded SQLite database, for deployments without Redis.
+// The connection is only ever used through `&mut self`, the mutex merely
+// makes the manager `Sync`.
+pub struct SqliteAuthManager {
+    con: Mutex<Connection>,
+    token_config: TokenConfig,
+}
+
+impl SqliteAuthManager {
+    pub fn new(
+        path: &str,
+        token_config: TokenConfig,
+    ) -> Result<SqliteAuthManager, Box<dyn std::error::Error>> {
+        let mut con = Connection::open(path)?;
+        con.pragma_update(None, "foreign_keys", true)?;
+        con.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut con)?;
+
+        Ok(SqliteAuthManager {
+            con: Mutex::new(con),
+            token_config,
+        })
+    }
+}
+
+#[async_trait]
+impl AuthManager for SqliteAuthManager {
+    async fn register(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        if username.contains('_') {
+            return Err("Username contains illegal character: _".into());
+        }
+
+        let con = self.con.get_mut().unwrap();
+        let key = HS256Key::generate();
+        let roles = vec![Role::Player];
+        let hash = password::hash_password(&password)?;
+        insert_user(con, &username, &hash, &key, &roles, None, None)?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn login(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<LoginOutcome, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) => user,
+            None => {
+                password::dummy_verify(&password);
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        };
+        match password::verify_password(&password, &user.password) {
+            Verification::Valid => (),
+            Verification::ValidNeedsRehash => set_password(con, &username, &password)?,
+            Verification::Invalid => return Err(INVALID_CREDENTIALS.into()),
+        }
+        let key = HS256Key::from_bytes(&user.key);
+        let two_factor = con
+            .query_row(
+                "SELECT 1 FROM totp WHERE username = ?1",
+                [&username],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if two_factor {
+            let token = token::create_two_factor_token(&key, &self.token_config, &username)?;
+            return Ok(LoginOutcome::TwoFactorRequired(token));
+        }
+        let token = token::create_token(&key, &self.token_config, &username, &user.roles)?;
+        Ok(LoginOutcome::Token(token))
+    }
+
+    async fn verify_second_factor(
+        &mut self,
+        username: String,
+        two_factor_token: String,
+        code: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) => user,
+            None => return Err("invalid token".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims =
+            token::verify_two_factor_token(&key, &self.token_config, &username, &two_factor_token)?;
+        if is_revoked(con, &username, &claims)? {
+            return Err("invalid token".into());
+        }
+        let totp: Option<(String, u64)> = con
+            .query_row(
+                "SELECT secret, last_step FROM totp WHERE username = ?1",
+                [&username],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (secret, last_step) = match totp {
+            Some(totp) => totp,
+            None => return Err("2FA is not enabled".into()),
+        };
+
+        if let Some(step) = two_factor::verify_code(&secret, &code, last_step) {
+            con.execute(
+                "UPDATE totp SET last_step = ?2 WHERE username = ?1",
+                params![username, step],
+            )?;
+        } else {
+            let mut statement =
+                con.prepare("SELECT id, hash FROM recovery_codes WHERE username = ?1")?;
+            let codes = statement
+                .query_map([&username], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<(i64, String)>, _>>()?;
+            let hashes: Vec<String> = codes.iter().map(|(_, hash)| hash.clone()).collect();
+            match two_factor::find_recovery_code(&code, &hashes) {
+                Some(i) => {
+                    con.execute("DELETE FROM recovery_codes WHERE id = ?1", [codes[i].0])?;
+                }
+                None => return Err("Invalid code".into()),
+            }
+        }
+        // The challenge can only be completed once
+        revoke(con, &username, &claims, &self.token_config)?;
+        token::create_token(&key, &self.token_config, &username, &user.roles)
+    }
+
+    async fn create_guest(
+        &mut self,
+        lifetime: u64,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let username = GUEST_PREFIX.to_owned() + &token::generate_id()[0..8];
+        let key = HS256Key::generate();
+        let roles = vec![Role::Player, Role::Guest];
+        let expires_at = token::now_secs() + lifetime;
+        // Nobody knows this password, guests only have their token
+        let hash = password::hash_password(&token::generate_id())?;
+        insert_user(con, &username, &hash, &key, &roles, None, Some(expires_at))?;
+        let token =
+            token::create_guest_token(&key, &self.token_config, &username, &roles, expires_at)?;
+        Ok((username, token))
+    }
+
+    async fn count_guests(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let count: usize = con.query_row(
+            "SELECT COUNT(*) FROM users WHERE guest_expires_at > ?1",
+            [token::now_secs()],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    async fn remove_expired_guests(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let now = token::now_secs();
+        let tx = con.transaction()?;
+        let expired = {
+            let mut statement =
+                tx.prepare("SELECT username FROM users WHERE guest_expires_at <= ?1")?;
+            let expired = statement
+                .query_map([now], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            expired
+        };
+        tx.execute("DELETE FROM users WHERE guest_expires_at <= ?1", [now])?;
+        tx.commit()?;
+        Ok(expired)
+    }
+
+    async fn upgrade_guest(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        match load_user(con, &username)? {
+            Some(user) if user.guest_expires_at.is_some() && !user.is_expired_guest() => (),
+            _ => return Err("Not a guest".into()),
+        }
+        let hash = password::hash_password(&password)?;
+        let tx = con.transaction()?;
+        tx.execute(
+            "UPDATE users SET password = ?2, guest_expires_at = NULL WHERE username = ?1",
+            params![username, hash],
+        )?;
+        tx.execute(
+            "DELETE FROM roles WHERE username = ?1 AND role = ?2",
+            params![username, Role::Guest.to_string()],
+        )?;
+        let key = rotate_key(&tx, &username)?;
+        let roles = load_roles(&tx, &username)?;
+        tx.commit()?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn find_linked_user(
+        &mut self,
+        subject: String,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        Ok(con
+            .query_row(
+                "SELECT username FROM users WHERE oidc_subject = ?1",
+                [subject],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    async fn external_login(
+        &mut self,
+        subject: String,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let linked = self.find_linked_user(subject.clone()).await?;
+        let con = self.con.get_mut().unwrap();
+        match linked {
+            Some(linked) if linked == username => {
+                let user = match load_user(con, &username)? {
+                    Some(user) => user,
+                    None => return Err("User does not exist".into()),
+                };
+                let key = HS256Key::from_bytes(&user.key);
+                return token::create_token(&key, &self.token_config, &username, &user.roles);
+            }
+            Some(_) => return Err("Identity is linked to another user".into()),
+            None => (),
+        }
+        if username.contains('_') {
+            return Err("Username contains illegal character: _".into());
+        }
+
+        let key = HS256Key::generate();
+        let roles = vec![Role::Player];
+        // Nobody knows this password, the user logs in through the provider
+        let hash = password::hash_password(&token::generate_id())?;
+        insert_user(con, &username, &hash, &key, &roles, Some(&subject), None)?;
+        token::create_token(&key, &self.token_config, &username, &roles)
+    }
+
+    async fn validate_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<TokenClaims, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) if !user.is_expired_guest() => user,
+            _ => return Err("invalid token".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims = token::verify_token(&key, &self.token_config, &username, &token)?;
+        if is_revoked(con, &username, &claims)? {
+            return Err("invalid token".into());
+        }
+        Ok(claims.custom)
+    }
+
+    async fn revoke_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) => user,
+            None => return Err("User does not exist".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims = token::verify_refreshable_token(&key, &self.token_config, &username, &token)?;
+        revoke(con, &username, &claims, &self.token_config)
+    }
+
+    async fn refresh_token(
+        &mut self,
+        username: String,
+        token: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) if !user.is_expired_guest() => user,
+            _ => return Err("invalid token".into()),
+        };
+        let key = HS256Key::from_bytes(&user.key);
+        let claims = token::verify_refreshable_token(&key, &self.token_config, &username, &token)?;
+        if is_revoked(con, &username, &claims)? {
+            return Err("invalid token".into());
+        }
+
+        let new_token = match user.guest_expires_at {
+            Some(expires_at) => token::create_guest_token(
+                &key,
+                &self.token_config,
+                &username,
+                &user.roles,
+                expires_at,
+            )?,
+            None => {
+                token::refresh_token(&key, &self.token_config, &username, &user.roles, &claims)?
+            }
+        };
+        revoke(con, &username, &claims, &self.token_config)?;
+        Ok(new_token)
+    }
+
+    async fn revoke_all_tokens(
+        &mut self,
+        username: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        rotate_key(con, &username)?;
+        Ok(())
+    }
+
+    async fn change_password(
+        &mut self,
+        username: String,
+        current_password: Option<String>,
+        new_password: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) => user,
+            None => return Err(INVALID_CREDENTIALS.into()),
+        };
+        if let Some(current_password) = current_password {
+            if password::verify_password(&current_password, &user.password) == Verification::Invalid
+            {
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        }
+        let tx = con.transaction()?;
+        set_password(&tx, &username, &new_password)?;
+        tx.execute(
+            "DELETE FROM password_resets WHERE username = ?1",
+            [&username],
+        )?;
+        let key = rotate_key(&tx, &username)?;
+        tx.commit()?;
+        token::create_token(&key, &self.token_config, &username, &user.roles)
+    }
+
+    async fn create_password_reset(
+        &mut self,
+        username: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        let reset_token = token::generate_id();
+        con.execute(
+            "INSERT OR REPLACE INTO password_resets (username, hash, expires_at)
+             VALUES (?1, ?2, ?3)",
+            params![
+                username,
+                password::hash_password(&reset_token)?,
+                token::now_secs() + PASSWORD_RESET_TTL
+            ],
+        )?;
+        Ok(reset_token)
+    }
+
+    async fn reset_password(
+        &mut self,
+        username: String,
+        reset_token: String,
+        new_password: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let reset: Option<(String, u64)> = con
+            .query_row(
+                "SELECT hash, expires_at FROM password_resets WHERE username = ?1",
+                [&username],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match reset {
+            Some((hash, expires_at))
+                if expires_at > token::now_secs()
+                    && password::verify_password(&reset_token, &hash) != Verification::Invalid => {}
+            _ => return Err("Invalid reset token".into()),
+        }
+        let tx = con.transaction()?;
+        tx.execute(
+            "DELETE FROM password_resets WHERE username = ?1",
+            [&username],
+        )?;
+        set_password(&tx, &username, &new_password)?;
+        rotate_key(&tx, &username)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn delete_user(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) => user,
+            None => return Err(INVALID_CREDENTIALS.into()),
+        };
+        if let Some(password) = password {
+            if password::verify_password(&password, &user.password) == Verification::Invalid {
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        }
+        let tx = con.transaction()?;
+        // Everything else is deleted by the foreign keys
+        tx.execute("DELETE FROM users WHERE username = ?1", [&username])?;
+        tx.execute(
+            "DELETE FROM login_failures WHERE key = ?1",
+            [login_throttle::user_key(&username)],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn create_api_key(
+        &mut self,
+        username: String,
+        name: String,
+        scopes: Vec<ApiKeyScope>,
+    ) -> Result<(ApiKeyInfo, String), Box<dyn std::error::Error>> {
+        api_key::validate_name(&name)?;
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        let count: usize = con.query_row(
+            "SELECT COUNT(*) FROM api_keys WHERE username = ?1",
+            [&username],
+            |row| row.get(0),
+        )?;
+        if count >= api_key::MAX_API_KEYS {
+            return Err("Too many API keys".into());
+        }
+        let info = ApiKeyInfo {
+            id: token::generate_id(),
+            name,
+            scopes,
+            created_at: token::now_secs(),
+        };
+        let (key, hash) = api_key::generate(&info.id);
+        con.execute(
+            "INSERT INTO api_keys (id, username, name, scopes, created_at, hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                info.id,
+                username,
+                info.name,
+                serde_json::to_string(&info.scopes)?,
+                info.created_at,
+                hash
+            ],
+        )?;
+        Ok((info, key))
+    }
+
+    async fn list_api_keys(
+        &mut self,
+        username: String,
+    ) -> Result<Vec<ApiKeyInfo>, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        let mut statement = con.prepare(
+            "SELECT id, name, scopes, created_at FROM api_keys
+             WHERE username = ?1 ORDER BY created_at, rowid",
+        )?;
+        let rows = statement
+            .query_map([&username], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, u64>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut keys = Vec::new();
+        for (id, name, scopes, created_at) in rows {
+            keys.push(ApiKeyInfo {
+                id,
+                name,
+                scopes: serde_json::from_str(&scopes)?,
+                created_at,
+            });
+        }
+        Ok(keys)
+    }
+
+    async fn revoke_api_key(
+        &mut self,
+        username: String,
+        id: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        let deleted = con.execute(
+            "DELETE FROM api_keys WHERE id = ?1 AND username = ?2",
+            params![id, username],
+        )?;
+        if deleted == 0 {
+            return Err("API key does not exist".into());
+        }
+        Ok(())
+    }
+
+    async fn validate_api_key(
+        &mut self,
+        key: String,
+    ) -> Result<(UserInfo, Vec<ApiKeyScope>), Box<dyn std::error::Error>> {
+        let (id, secret) = match api_key::parse(&key) {
+            Some(parsed) => parsed,
+            None => return Err("invalid API key".into()),
+        };
+        let con = self.con.get_mut().unwrap();
+        let stored: Option<(String, String, String)> = con
+            .query_row(
+                "SELECT username, scopes, hash FROM api_keys WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let (username, scopes) = match stored {
+            Some((username, scopes, hash)) if api_key::verify_secret(secret, &hash) => {
+                (username, scopes)
+            }
+            _ => return Err("invalid API key".into()),
+        };
+        let owner = UserInfo {
+            roles: load_roles(con, &username)?,
+            username,
+        };
+        Ok((owner, serde_json::from_str(&scopes)?))
+    }
+
+    async fn begin_totp_enrollment(
+        &mut self,
+        username: String,
+    ) -> Result<TotpEnrollment, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        let enabled = con
+            .query_row(
+                "SELECT 1 FROM totp WHERE username = ?1",
+                [&username],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if enabled {
+            return Err("2FA is already enabled".into());
+        }
+        let secret = two_factor::generate_secret();
+        let enrollment = two_factor::enrollment(&secret, &self.token_config.issuer, &username)?;
+        con.execute(
+            "INSERT OR REPLACE INTO pending_totp (username, secret, expires_at)
+             VALUES (?1, ?2, ?3)",
+            params![
+                username,
+                secret,
+                token::now_secs() + two_factor::ENROLLMENT_TTL
+            ],
+        )?;
+        Ok(enrollment)
+    }
+
+    async fn confirm_totp_enrollment(
+        &mut self,
+        username: String,
+        code: String,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        let pending: Option<(String, u64)> = con
+            .query_row(
+                "SELECT secret, expires_at FROM pending_totp WHERE username = ?1",
+                [&username],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let secret = match pending {
+            Some((secret, expires_at)) if expires_at > token::now_secs() => secret,
+            _ => return Err("No pending 2FA enrollment".into()),
+        };
+        let last_step = match two_factor::verify_code(&secret, &code, 0) {
+            Some(step) => step,
+            None => return Err("Invalid code".into()),
+        };
+        let codes = two_factor::generate_recovery_codes();
+        let hashes: Vec<String> = codes
+            .iter()
+            .map(|code| two_factor::hash_recovery_code(code))
+            .collect();
+
+        let tx = con.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO totp (username, secret, last_step) VALUES (?1, ?2, ?3)",
+            params![username, secret, last_step],
+        )?;
+        tx.execute(
+            "DELETE FROM recovery_codes WHERE username = ?1",
+            [&username],
+        )?;
+        for hash in hashes {
+            tx.execute(
+                "INSERT INTO recovery_codes (username, hash) VALUES (?1, ?2)",
+                params![username, hash],
+            )?;
+        }
+        tx.execute("DELETE FROM pending_totp WHERE username = ?1", [&username])?;
+        tx.commit()?;
+        Ok(codes)
+    }
+
+    async fn disable_totp(
+        &mut self,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let user = match load_user(con, &username)? {
+            Some(user) => user,
+            None => return Err(INVALID_CREDENTIALS.into()),
+        };
+        if let Some(password) = password {
+            if password::verify_password(&password, &user.password) == Verification::Invalid {
+                return Err(INVALID_CREDENTIALS.into());
+            }
+        }
+        let tx = con.transaction()?;
+        for table in ["totp", "recovery_codes", "pending_totp"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE username = ?1", table),
+                [&username],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn list_users(&mut self) -> Result<Vec<UserInfo>, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let mut statement = con.prepare("SELECT username FROM users ORDER BY username")?;
+        let usernames = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        let mut users = Vec::new();
+        for username in usernames {
+            users.push(UserInfo {
+                roles: load_roles(con, &username)?,
+                username,
+            });
+        }
+        Ok(users)
+    }
+
+    async fn grant_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        con.execute(
+            "INSERT OR IGNORE INTO roles (username, role) VALUES (?1, ?2)",
+            params![username, role.to_string()],
+        )?;
+        Ok(())
+    }
+
+    async fn revoke_role(
+        &mut self,
+        username: String,
+        role: Role,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        if !user_exists(con, &username)? {
+            return Err("User does not exist".into());
+        }
+        con.execute(
+            "DELETE FROM roles WHERE username = ?1 AND role = ?2",
+            params![username, role.to_string()],
+        )?;
+        self.revoke_all_tokens(username).await
+    }
+
+    async fn get_login_attempts(
+        &mut self,
+        key: String,
+    ) -> Result<LoginAttempts, Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let attempts = con
+            .query_row(
+                "SELECT failures, last_failure FROM login_failures
+                 WHERE key = ?1 AND expires_at > ?2",
+                params![key, token::now_secs()],
+                |row| {
+                    Ok(LoginAttempts {
+                        failures: row.get(0)?,
+                        last_failure: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(attempts.unwrap_or_default())
+    }
+
+    async fn record_failed_login(
+        &mut self,
+        key: String,
+        expire_after: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        let now = token::now_secs();
+        con.execute("DELETE FROM login_failures WHERE expires_at <= ?1", [now])?;
+        con.execute(
+            "INSERT INTO login_failures (key, failures, last_failure, expires_at)
+             VALUES (?1, 1, ?2, ?3)
+             ON CONFLICT (key) DO UPDATE SET
+                 failures = failures + 1,
+                 last_failure = excluded.last_failure,
+                 expires_at = excluded.expires_at",
+            params![key, now, now + expire_after],
+        )?;
+        Ok(())
+    }
+
+    async fn clear_failed_logins(&mut self, key: String) -> Result<(), Box<dyn std::error::Error>> {
+        let con = self.con.get_mut().unwrap();
+        con.execute("DELETE FROM login_failures WHERE key = ?1", [key])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn auth() -> SqliteAuthManager {
+        SqliteAuthManager::new(":memory:", TokenConfig::default()).unwrap()
+    }
+
+    fn session_token(outcome: LoginOutcome) -> String {
+        match outcome {
+            LoginOutcome::Token(token) => token,
+            LoginOutcome::TwoFactorRequired(_) => panic!("Unexpected 2FA challenge"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let mut auth = auth();
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+
+        let token = session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+        let wrong_password = auth
+            .login("alice".to_string(), "hunter3".to_string())
+            .await
+            .unwrap_err();
+        let unknown_user = auth
+            .login("bob".to_string(), "hunter2".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(wrong_password.to_string(), unknown_user.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_register_existing() {
+        let mut auth = auth();
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let err = auth
+            .register("alice".to_string(), "other".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "User already exists");
+        let err = auth
+            .external_login("subject".to_string(), "alice".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "User already exists");
+        assert!(auth
+            .register("al_ice".to_string(), "hunter2".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_and_refresh() {
+        let mut auth = auth();
+        let first = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let second = session_token(
+            auth.login("alice".to_string(), "hunter2".to_string())
+                .await
+                .unwrap(),
+        );
+        auth.revoke_token("alice".to_string(), first.clone())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), first)
+            .await
+            .is_err());
+
+        let refreshed = auth
+            .refresh_token("alice".to_string(), second.clone())
+            .await
+            .unwrap();
+        assert!(auth
+            .refresh_token("alice".to_string(), second)
+            .await
+            .is_err());
+
+        auth.revoke_all_tokens("alice".to_string()).await.unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), refreshed)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_roles() {
+        let mut auth = auth();
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        auth.grant_role("alice".to_string(), Role::Admin)
+            .await
+            .unwrap();
+        auth.grant_role("alice".to_string(), Role::Admin)
+            .await
+            .unwrap();
+        assert_eq!(
+            auth.list_users().await.unwrap(),
+            vec![UserInfo {
+                username: "alice".to_string(),
+                roles: vec![Role::Player, Role::Admin],
+            }]
+        );
+        let token = auth
+            .refresh_token("alice".to_string(), token)
+            .await
+            .unwrap();
+        let claims = auth
+            .validate_token("alice".to_string(), token.clone())
+            .await
+            .unwrap();
+        assert_eq!(claims.roles, vec![Role::Player, Role::Admin]);
+
+        auth.revoke_role("alice".to_string(), Role::Admin)
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_err());
+        assert!(auth
+            .grant_role("bob".to_string(), Role::Admin)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_and_delete() {
+        let mut auth = auth();
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let reset_token = auth
+            .create_password_reset("alice".to_string())
+            .await
+            .unwrap();
+        auth.reset_password(
+            "alice".to_string(),
+            reset_token.clone(),
+            "hunter4".to_string(),
+        )
+        .await
+        .unwrap();
+        assert!(auth
+            .reset_password("alice".to_string(), reset_token, "hunter5".to_string())
+            .await
+            .is_err());
+
+        auth.create_api_key("alice".to_string(), "ci".to_string(), Vec::new())
+            .await
+            .unwrap();
+        assert!(auth
+            .delete_user("alice".to_string(), Some("hunter2".to_string()))
+            .await
+            .is_err());
+        auth.delete_user("alice".to_string(), Some("hunter4".to_string()))
+            .await
+            .unwrap();
+        assert!(auth
+            .login("alice".to_string(), "hunter4".to_string())
+            .await
+            .is_err());
+        // Child rows went with the user, so the name can be registered again
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(auth
+            .list_api_keys("alice".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_two_factor() {
+        let mut auth = auth();
+        auth.register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        let enrollment = auth
+            .begin_totp_enrollment("alice".to_string())
+            .await
+            .unwrap();
+        let codes = auth
+            .confirm_totp_enrollment(
+                "alice".to_string(),
+                two_factor::current_code(&enrollment.secret),
+            )
+            .await
+            .unwrap();
+
+        let challenge = match auth
+            .login("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap()
+        {
+            LoginOutcome::TwoFactorRequired(token) => token,
+            LoginOutcome::Token(_) => panic!("2FA challenge expected"),
+        };
+        let token = auth
+            .verify_second_factor("alice".to_string(), challenge.clone(), codes[0].clone())
+            .await
+            .unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+        // Neither the challenge nor the recovery code can be reused
+        assert!(auth
+            .verify_second_factor("alice".to_string(), challenge, codes[1].clone())
+            .await
+            .is_err());
+        let challenge = match auth
+            .login("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap()
+        {
+            LoginOutcome::TwoFactorRequired(token) => token,
+            LoginOutcome::Token(_) => panic!("2FA challenge expected"),
+        };
+        assert!(auth
+            .verify_second_factor("alice".to_string(), challenge, codes[0].clone())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_guests_and_api_keys() {
+        let mut auth = auth();
+        let (username, _) = auth.create_guest(60).await.unwrap();
+        auth.create_guest(0).await.unwrap();
+        assert_eq!(auth.count_guests().await.unwrap(), 1);
+        assert_eq!(auth.remove_expired_guests().await.unwrap().len(), 1);
+
+        auth.upgrade_guest(username.clone(), "hunter2".to_string())
+            .await
+            .unwrap();
+        assert_eq!(auth.count_guests().await.unwrap(), 0);
+        let (info, key) = auth
+            .create_api_key(
+                username.clone(),
+                "ci".to_string(),
+                vec![ApiKeyScope::InstanceStart],
+            )
+            .await
+            .unwrap();
+        let (owner, scopes) = auth.validate_api_key(key.clone()).await.unwrap();
+        assert_eq!(owner.username, username);
+        assert_eq!(owner.roles, vec![Role::Player]);
+        assert_eq!(scopes, vec![ApiKeyScope::InstanceStart]);
+
+        auth.revoke_api_key(username.clone(), info.id.clone())
+            .await
+            .unwrap();
+        assert!(auth.validate_api_key(key).await.is_err());
+        assert!(auth.revoke_api_key(username, info.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let path = env::temp_dir()
+            .join(format!("lynx-auth-{}.db", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut auth = SqliteAuthManager::new(&path, TokenConfig::default()).unwrap();
+        let token = auth
+            .register("alice".to_string(), "hunter2".to_string())
+            .await
+            .unwrap();
+        auth.record_failed_login("user:alice".to_string(), 60)
+            .await
+            .unwrap();
+        drop(auth);
+
+        // Reopening does not apply the migrations again
+        let mut auth = SqliteAuthManager::new(&path, TokenConfig::default()).unwrap();
+        assert!(auth
+            .validate_token("alice".to_string(), token)
+            .await
+            .is_ok());
+        let attempts = auth
+            .get_login_attempts("user:alice".to_string())
+            .await
+            .unwrap();
+        assert_eq!(attempts.failures, 1);
+        drop(auth);
+
+        let con = Connection::open(&path).unwrap();
+        con.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(con);
+        assert!(SqliteAuthManager::new(&path, TokenConfig::default()).is_err());
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(path.clone() + suffix);
+        }
+    }
+}
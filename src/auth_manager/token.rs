@@ -0,0 +1,458 @@
+use crate::auth_manager::role::Role;
+use crate::auth_manager::signing_keys::SigningKeys;
+
+use jwt_simple::prelude::*;
+use jwt_simple::JWTError;
+use rand::Rng;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+// Tokens are signed with a per-user HS256 key which each `AuthManager`
+// stores next to the password hash. Every token carries a random `jti`,
+// so that single tokens can be put on a denylist until they expire.
+// `sub` is the username, `iss`/`aud` identify the deployment.
+// With `signing_keys` configured, tokens are signed with those instead and
+// carry a fingerprint of the per-user key as `generation`, so that rotating
+// the per-user key still invalidates all tokens of the user.
+
+pub const SESSION_SCOPE: &str = "session";
+// Issued after a correct password when 2FA is enabled, only good for
+// `AuthManager::verify_second_factor`
+pub const TWO_FACTOR_SCOPE: &str = "2fa";
+const TWO_FACTOR_LIFETIME_MINS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenClaims {
+    pub scope: String,
+    // When the user logged in, kept across refreshes, as unix timestamp
+    pub auth_time: u64,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenConfig {
+    pub issuer: String,
+    pub audience: String,
+    // Tolerated clock skew when checking `exp` and `nbf`
+    pub leeway: Duration,
+    pub lifetime: Duration,
+    // How long after expiring a token can still be refreshed
+    pub refresh_grace: Duration,
+    // No token is issued past `auth_time` + `max_session`
+    pub max_session: Duration,
+    pub signing_keys: Option<Arc<SigningKeys>>,
+}
+
+impl Default for TokenConfig {
+    fn default() -> TokenConfig {
+        TokenConfig {
+            issuer: "lynx-balancer".to_string(),
+            audience: "lynx".to_string(),
+            leeway: Duration::from_secs(60),
+            lifetime: Duration::from_hours(2),
+            refresh_grace: Duration::from_mins(10),
+            max_session: Duration::from_hours(24),
+            signing_keys: None,
+        }
+    }
+}
+
+pub fn create_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    roles: &[Role],
+) -> Result<String, Box<dyn std::error::Error>> {
+    issue_session_token(key, config, username, roles, now_secs())
+}
+
+// Like `create_token`, but the token never outlives `expires_at`
+pub fn create_guest_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    roles: &[Role],
+    expires_at: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let custom = TokenClaims {
+        scope: SESSION_SCOPE.to_string(),
+        auth_time: now_secs(),
+        roles: roles.to_vec(),
+        generation: None,
+    };
+    let left = Duration::from_secs(expires_at.saturating_sub(now_secs()));
+    issue_token(key, config, username, custom, config.lifetime.min(left))
+}
+
+pub fn verify_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    token: &str,
+) -> Result<JWTClaims<TokenClaims>, Box<dyn std::error::Error>> {
+    verify_with_tolerance(key, config, username, token, SESSION_SCOPE, config.leeway)
+}
+
+pub fn create_two_factor_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let custom = TokenClaims {
+        scope: TWO_FACTOR_SCOPE.to_string(),
+        auth_time: now_secs(),
+        roles: Vec::new(),
+        generation: None,
+    };
+    let lifetime = Duration::from_mins(TWO_FACTOR_LIFETIME_MINS);
+    issue_token(key, config, username, custom, lifetime)
+}
+
+pub fn verify_two_factor_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    token: &str,
+) -> Result<JWTClaims<TokenClaims>, Box<dyn std::error::Error>> {
+    verify_with_tolerance(
+        key,
+        config,
+        username,
+        token,
+        TWO_FACTOR_SCOPE,
+        config.leeway,
+    )
+}
+
+// Like `verify_token`, but also accepts tokens expired less than `refresh_grace` ago
+pub fn verify_refreshable_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    token: &str,
+) -> Result<JWTClaims<TokenClaims>, Box<dyn std::error::Error>> {
+    let tolerance = config.leeway + config.refresh_grace;
+    verify_with_tolerance(key, config, username, token, SESSION_SCOPE, tolerance)
+}
+
+// Issues a successor of an already verified token, keeping its `auth_time`.
+// `roles` are the user's current roles, which might have changed since.
+pub fn refresh_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    roles: &[Role],
+    claims: &JWTClaims<TokenClaims>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let auth_time = claims.custom.auth_time;
+    if now_secs() >= auth_time + config.max_session.as_secs() {
+        return Err("session expired".into());
+    }
+    issue_session_token(key, config, username, roles, auth_time)
+}
+
+// Seconds until the token can no longer be used, not even for a refresh.
+// Used as TTL of denylist entries.
+pub fn denylist_ttl(claims: &JWTClaims<TokenClaims>, config: &TokenConfig) -> u64 {
+    match claims.expires_at {
+        Some(expires_at) => (expires_at + config.leeway + config.refresh_grace)
+            .as_secs()
+            .saturating_sub(now_secs()),
+        None => 0,
+    }
+}
+
+// Reads `sub` without checking the signature, only to find out
+// which user's key the token has to be verified with
+pub fn unverified_subject(token: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Subject {
+        sub: Option<String>,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let bytes = Base64UrlSafeNoPadding::decode_to_vec(payload, None).ok()?;
+    serde_json::from_slice::<Subject>(&bytes).ok()?.sub
+}
+
+pub fn now_secs() -> u64 {
+    Clock::now_since_epoch().as_secs()
+}
+
+fn issue_session_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    roles: &[Role],
+    auth_time: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let session_left = (auth_time + config.max_session.as_secs()).saturating_sub(now_secs());
+    let lifetime = config.lifetime.min(Duration::from_secs(session_left));
+    let custom = TokenClaims {
+        scope: SESSION_SCOPE.to_string(),
+        auth_time,
+        roles: roles.to_vec(),
+        generation: None,
+    };
+    issue_token(key, config, username, custom, lifetime)
+}
+
+fn issue_token(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    mut custom: TokenClaims,
+    lifetime: Duration,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if config.signing_keys.is_some() {
+        custom.generation = Some(generation(key));
+    }
+    let claims = Claims::with_custom_claims(custom, lifetime)
+        .with_jwt_id(generate_id())
+        .with_subject(username)
+        .with_issuer(&config.issuer)
+        .with_audience(&config.audience);
+    let token = match &config.signing_keys {
+        Some(signing_keys) => signing_keys.sign(claims)?,
+        None => key.authenticate(claims)?,
+    };
+    Ok(token)
+}
+
+// Identifies the per-user key without revealing it
+fn generation(key: &HS256Key) -> String {
+    let mac = hmac_sha256::HMAC::mac(b"lynx token generation", key.to_bytes());
+    mac[0..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn verify_with_tolerance(
+    key: &HS256Key,
+    config: &TokenConfig,
+    username: &str,
+    token: &str,
+    scope: &str,
+    tolerance: Duration,
+) -> Result<JWTClaims<TokenClaims>, Box<dyn std::error::Error>> {
+    let options = VerificationOptions {
+        required_subject: Some(username.to_string()),
+        allowed_issuers: Some(HashSet::from([config.issuer.clone()])),
+        allowed_audiences: Some(HashSet::from([config.audience.clone()])),
+        time_tolerance: Some(tolerance),
+        ..Default::default()
+    };
+    let (ret, generation) = match &config.signing_keys {
+        Some(signing_keys) => (signing_keys.verify(token, options), Some(generation(key))),
+        None => (key.verify_token::<TokenClaims>(token, Some(options)), None),
+    };
+    match ret {
+        Ok(claims)
+            if claims.jwt_id.is_some()
+                && claims.custom.scope == scope
+                && claims.custom.generation == generation =>
+        {
+            Ok(claims)
+        }
+        Err(e)
+            if matches!(
+                e.downcast_ref::<JWTError>(),
+                Some(JWTError::TokenHasExpired)
+            ) =>
+        {
+            Err("token expired".into())
+        }
+        _ => Err("invalid token".into()),
+    }
+}
+
+// 128 random bits, hex encoded
+pub fn generate_id() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        let claims = verify_token(&key, &config, "alice", &token).unwrap();
+        assert_eq!(claims.subject, Some("alice".to_string()));
+        assert_eq!(claims.custom.scope, SESSION_SCOPE);
+        assert_eq!(claims.custom.roles, vec![Role::Player]);
+    }
+
+    #[test]
+    fn test_verify_other_subject() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        assert!(verify_token(&key, &config, "bob", &token).is_err());
+    }
+
+    #[test]
+    fn test_verify_other_deployment() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+
+        let other_issuer = TokenConfig {
+            issuer: "other-balancer".to_string(),
+            ..TokenConfig::default()
+        };
+        assert!(verify_token(&key, &other_issuer, "alice", &token).is_err());
+
+        let other_audience = TokenConfig {
+            audience: "other".to_string(),
+            ..TokenConfig::default()
+        };
+        assert!(verify_token(&key, &other_audience, "alice", &token).is_err());
+    }
+
+    #[test]
+    fn test_verify_other_scope() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let custom = TokenClaims {
+            scope: "other".to_string(),
+            auth_time: now_secs(),
+            roles: vec![Role::Player],
+            generation: None,
+        };
+        let claims = Claims::with_custom_claims(custom, Duration::from_hours(2))
+            .with_jwt_id(generate_id())
+            .with_subject("alice")
+            .with_issuer(&config.issuer)
+            .with_audience(&config.audience);
+        let token = key.authenticate(claims).unwrap();
+        assert!(verify_token(&key, &config, "alice", &token).is_err());
+    }
+
+    #[test]
+    fn test_two_factor_token() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let token = create_two_factor_token(&key, &config, "alice").unwrap();
+        assert!(verify_two_factor_token(&key, &config, "alice", &token).is_ok());
+        assert!(verify_token(&key, &config, "alice", &token).is_err());
+        assert!(verify_refreshable_token(&key, &config, "alice", &token).is_err());
+
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        assert!(verify_two_factor_token(&key, &config, "alice", &token).is_err());
+    }
+
+    #[test]
+    fn test_signing_keys() {
+        let key = HS256Key::generate();
+        let config = TokenConfig {
+            signing_keys: Some(Arc::new(SigningKeys::generate("current"))),
+            ..TokenConfig::default()
+        };
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        let metadata = Token::decode_metadata(&token).unwrap();
+        assert_eq!(metadata.algorithm(), "ES256");
+        assert_eq!(metadata.key_id(), Some("current"));
+        let claims = verify_token(&key, &config, "alice", &token).unwrap();
+        assert!(claims.custom.generation.is_some());
+
+        // Rotating the per-user key still revokes the token
+        assert!(verify_token(&HS256Key::generate(), &config, "alice", &token).is_err());
+        // HS256 tokens are no longer accepted and vice versa
+        let hs256_token =
+            create_token(&key, &TokenConfig::default(), "alice", &[Role::Player]).unwrap();
+        assert!(verify_token(&key, &config, "alice", &hs256_token).is_err());
+        assert!(verify_token(&key, &TokenConfig::default(), "alice", &token).is_err());
+        // Neither are tokens of unknown keys
+        let other_config = TokenConfig {
+            signing_keys: Some(Arc::new(SigningKeys::generate("current"))),
+            ..TokenConfig::default()
+        };
+        assert!(verify_token(&key, &other_config, "alice", &token).is_err());
+    }
+
+    #[test]
+    fn test_guest_token() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let expires_at = now_secs() + 60;
+        let token =
+            create_guest_token(&key, &config, "guest-1", &[Role::Guest], expires_at).unwrap();
+        let claims = verify_token(&key, &config, "guest-1", &token).unwrap();
+        assert_eq!(claims.expires_at.unwrap().as_secs(), expires_at);
+    }
+
+    #[test]
+    fn test_verify_expired() {
+        let key = HS256Key::generate();
+        let config = TokenConfig {
+            leeway: Duration::from_secs(0),
+            lifetime: Duration::from_secs(0),
+            ..TokenConfig::default()
+        };
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        assert_eq!(
+            verify_token(&key, &config, "alice", &token)
+                .unwrap_err()
+                .to_string(),
+            "token expired"
+        );
+        assert!(verify_refreshable_token(&key, &config, "alice", &token).is_ok());
+
+        let no_grace = TokenConfig {
+            refresh_grace: Duration::from_secs(0),
+            ..config
+        };
+        assert!(verify_refreshable_token(&key, &no_grace, "alice", &token).is_err());
+    }
+
+    #[test]
+    fn test_refresh_keeps_auth_time() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        let claims = verify_token(&key, &config, "alice", &token).unwrap();
+        let refreshed = refresh_token(&key, &config, "alice", &[Role::Player], &claims).unwrap();
+        let refreshed_claims = verify_token(&key, &config, "alice", &refreshed).unwrap();
+        assert_eq!(refreshed_claims.custom.auth_time, claims.custom.auth_time);
+        assert_ne!(refreshed_claims.jwt_id, claims.jwt_id);
+    }
+
+    #[test]
+    fn test_refresh_updates_roles() {
+        let key = HS256Key::generate();
+        let config = TokenConfig::default();
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        let claims = verify_token(&key, &config, "alice", &token).unwrap();
+        let roles = [Role::Player, Role::Admin];
+        let refreshed = refresh_token(&key, &config, "alice", &roles, &claims).unwrap();
+        let refreshed_claims = verify_token(&key, &config, "alice", &refreshed).unwrap();
+        assert_eq!(refreshed_claims.custom.roles, roles.to_vec());
+    }
+
+    #[test]
+    fn test_refresh_past_max_session() {
+        let key = HS256Key::generate();
+        let config = TokenConfig {
+            max_session: Duration::from_secs(0),
+            ..TokenConfig::default()
+        };
+        let token = create_token(&key, &config, "alice", &[Role::Player]).unwrap();
+        let claims = verify_refreshable_token(&key, &config, "alice", &token).unwrap();
+        assert!(refresh_token(&key, &config, "alice", &[Role::Player], &claims).is_err());
+    }
+
+    #[test]
+    fn test_unverified_subject() {
+        let key = HS256Key::generate();
+        let token = create_token(&key, &TokenConfig::default(), "alice", &[Role::Player]).unwrap();
+        assert_eq!(unverified_subject(&token), Some("alice".to_string()));
+        assert_eq!(unverified_subject("not a token"), None);
+    }
+}
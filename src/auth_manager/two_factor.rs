@@ -0,0 +1,160 @@
+use crate::auth_manager::token;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+// TOTP as in RFC 6238 with the parameters every authenticator app supports:
+// SHA1, 6 digits, 30 second steps. Secrets are stored base32 encoded.
+
+const STEP: u64 = 30;
+const DIGITS: usize = 6;
+const RECOVERY_CODES: usize = 10;
+// Seconds an unconfirmed enrollment is kept
+pub const ENROLLMENT_TTL: u64 = 10 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+pub fn enrollment(
+    secret: &str,
+    issuer: &str,
+    username: &str,
+) -> Result<TotpEnrollment, Box<dyn std::error::Error>> {
+    let totp = totp(secret, Some(issuer), username)?;
+    Ok(TotpEnrollment {
+        secret: secret.to_string(),
+        otpauth_uri: totp.get_url(),
+    })
+}
+
+// Returns the time step the code belongs to. Codes of the neighbouring steps
+// are accepted because of clock skew, codes of `last_step` or earlier are
+// rejected so that a code cannot be replayed.
+pub fn verify_code(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let totp = totp(secret, None, "").ok()?;
+    let current = token::now_secs() / STEP;
+    (current.saturating_sub(1)..=current + 1)
+        .filter(|step| *step > last_step)
+        .find(|step| {
+            let expected = totp.generate(step * STEP);
+            bool::from(expected.as_bytes().ct_eq(code.trim().as_bytes()))
+        })
+}
+
+// Shown to the user once, `AuthManager`s only store their hashes. Codes are
+// random 80 bit values, so like API key secrets they are hashed with SHA-256,
+// a slow password hash per stored code would stall every 2FA login.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let id = token::generate_id();
+            format!(
+                "{}-{}-{}-{}",
+                &id[0..5],
+                &id[5..10],
+                &id[10..15],
+                &id[15..20]
+            )
+        })
+        .collect()
+}
+
+pub fn hash_recovery_code(code: &str) -> String {
+    let code = code.trim().to_lowercase();
+    let hash = hmac_sha256::Hash::hash(code.as_bytes());
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Returns the index of the stored hash `code` matches
+pub fn find_recovery_code(code: &str, hashes: &[String]) -> Option<usize> {
+    let hash = hash_recovery_code(code);
+    hashes
+        .iter()
+        .position(|stored| bool::from(stored.as_bytes().ct_eq(hash.as_bytes())))
+}
+
+fn totp(
+    secret: &str,
+    issuer: Option<&str>,
+    username: &str,
+) -> Result<TOTP, Box<dyn std::error::Error>> {
+    let bytes = match Secret::Encoded(secret.to_string()).to_bytes() {
+        Ok(bytes) => bytes,
+        Err(_) => return Err("Invalid TOTP secret".into()),
+    };
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        1,
+        STEP,
+        bytes,
+        issuer.map(str::to_string),
+        username.to_string(),
+    )?;
+    Ok(totp)
+}
+
+// What an authenticator app would show right now
+#[cfg(test)]
+pub fn current_code(secret: &str) -> String {
+    totp(secret, None, "").unwrap().generate(token::now_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_code() {
+        let secret = generate_secret();
+        let code = current_code(&secret);
+        let step = verify_code(&secret, &code, 0).unwrap();
+        assert_eq!(step, token::now_secs() / STEP);
+        assert_eq!(verify_code(&secret, "000000x", 0), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        let secret = generate_secret();
+        let code = current_code(&secret);
+        let step = verify_code(&secret, &code, 0).unwrap();
+        assert_eq!(verify_code(&secret, &code, step), None);
+    }
+
+    #[test]
+    fn test_enrollment_uri() {
+        let secret = generate_secret();
+        let enrollment = enrollment(&secret, "lynx-balancer", "alice").unwrap();
+        assert!(enrollment
+            .otpauth_uri
+            .starts_with("otpauth://totp/lynx-balancer:alice?"));
+        assert!(enrollment.otpauth_uri.contains(&secret));
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(codes[0].len(), 23);
+        assert_ne!(codes[0], codes[1]);
+
+        let hashes: Vec<String> = codes[0..2]
+            .iter()
+            .map(|code| hash_recovery_code(code))
+            .collect();
+        assert_eq!(find_recovery_code(&codes[1], &hashes), Some(1));
+        let typed = format!(" {} ", codes[0].to_uppercase());
+        assert_eq!(find_recovery_code(&typed, &hashes), Some(0));
+        assert_eq!(find_recovery_code(&codes[2], &hashes), None);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::auth_manager::GUEST_PREFIX;
+
+use regex::Regex;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+// Usernames end up as Kubernetes object names and label values
+// (see `KubernetesHost`), so they always have to be DNS-1123 labels.
+// On top of that deployments can restrict them with a custom pattern.
+
+const DNS_1123_LABEL: &str = "^[a-z0-9]([-a-z0-9]*[a-z0-9])?$";
+const DNS_1123_MAX_LENGTH: usize = 63;
+
+fn dns_1123_label() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(DNS_1123_LABEL).unwrap())
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum UsernameViolation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    NotDns1123,
+    PatternMismatch { pattern: String },
+    Reserved,
+}
+
+#[derive(Clone, Debug)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub pattern: Option<Regex>,
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> UsernamePolicy {
+        UsernamePolicy {
+            min_length: 3,
+            max_length: 32,
+            pattern: None,
+            reserved: [
+                "admin", "root", "system", "guest", "guests", "audit", "lynx",
+            ]
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn validate(&self, username: &str) -> Result<(), Vec<UsernameViolation>> {
+        let mut violations = Vec::new();
+
+        let length = username.chars().count();
+        if length < self.min_length {
+            violations.push(UsernameViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        let max_length = self.max_length.min(DNS_1123_MAX_LENGTH);
+        if length > max_length {
+            violations.push(UsernameViolation::TooLong { max_length });
+        }
+        if !dns_1123_label().is_match(username) {
+            violations.push(UsernameViolation::NotDns1123);
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(username) {
+                violations.push(UsernameViolation::PatternMismatch {
+                    pattern: pattern.to_string(),
+                });
+            }
+        }
+        // Guest names are only handed out by `AuthManager::create_guest`
+        if self.reserved.iter().any(|name| name == username) || username.starts_with(GUEST_PREFIX) {
+            violations.push(UsernameViolation::Reserved);
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.validate("alice"), Ok(()));
+        assert_eq!(policy.validate("alice-2"), Ok(()));
+        assert_eq!(policy.validate("007"), Ok(()));
+    }
+
+    #[test]
+    fn test_length() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(
+            policy.validate("al"),
+            Err(vec![UsernameViolation::TooShort { min_length: 3 }])
+        );
+        assert_eq!(
+            policy.validate(&"a".repeat(33)),
+            Err(vec![UsernameViolation::TooLong { max_length: 32 }])
+        );
+        assert_eq!(
+            policy.validate(""),
+            Err(vec![
+                UsernameViolation::TooShort { min_length: 3 },
+                UsernameViolation::NotDns1123
+            ])
+        );
+    }
+
+    #[test]
+    fn test_max_length_capped_by_dns_1123() {
+        let policy = UsernamePolicy {
+            max_length: 100,
+            ..UsernamePolicy::default()
+        };
+        assert_eq!(
+            policy.validate(&"a".repeat(64)),
+            Err(vec![UsernameViolation::TooLong { max_length: 63 }])
+        );
+    }
+
+    #[test]
+    fn test_not_dns_1123() {
+        let policy = UsernamePolicy::default();
+        for username in [
+            "Alice", "al_ice", "al ice", "-alice", "alice-", "al.ice", "żaba",
+        ] {
+            assert_eq!(
+                policy.validate(username),
+                Err(vec![UsernameViolation::NotDns1123]),
+                "{}",
+                username
+            );
+        }
+    }
+
+    #[test]
+    fn test_pattern() {
+        let policy = UsernamePolicy {
+            pattern: Some(Regex::new("^s[0-9]+$").unwrap()),
+            ..UsernamePolicy::default()
+        };
+        assert_eq!(policy.validate("s12345"), Ok(()));
+        assert_eq!(
+            policy.validate("alice"),
+            Err(vec![UsernameViolation::PatternMismatch {
+                pattern: "^s[0-9]+$".to_string()
+            }])
+        );
+    }
+
+    #[test]
+    fn test_reserved() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(
+            policy.validate("admin"),
+            Err(vec![UsernameViolation::Reserved])
+        );
+        assert_eq!(
+            policy.validate("guests"),
+            Err(vec![UsernameViolation::Reserved])
+        );
+        assert_eq!(
+            policy.validate("audit"),
+            Err(vec![UsernameViolation::Reserved])
+        );
+        assert_eq!(
+            policy.validate("guest-1234"),
+            Err(vec![UsernameViolation::Reserved])
+        );
+    }
+
+    #[test]
+    fn test_violations_serialize() {
+        let json = serde_json::to_value(UsernameViolation::TooShort { min_length: 3 }).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"code": "too_short", "min_length": 3})
+        );
+    }
+}
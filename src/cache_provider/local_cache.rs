@@ -15,7 +15,7 @@ pub struct LocalCache<K: Eq + Hash, V> {
 impl<K: Eq + Hash, V> LocalCache<K, V> {
     pub fn new(cache_server_url: Option<String>) -> LocalCache<K, V> {
         LocalCache {
-            cache_server_url: cache_server_url,
+            cache_server_url,
             map: HashMap::new(),
         }
     }
@@ -40,20 +40,20 @@ impl<K: Sync + Send + Eq + Hash + ToString, V: Sync + Send + Clone + FromStr> Ca
             return Some(v.to_owned());
         }
 
-        let cache_server_url;
-        if let Some(url) = &self.cache_server_url {
-            cache_server_url = url;
-        } else {
-            error!("Tried `get_or_query` on LocalCache without `cache_server_url`!");
-            return None;
-        }
+        let cache_server_url = match &self.cache_server_url {
+            Some(url) => url,
+            None => {
+                error!("Tried `get_or_query` on LocalCache without `cache_server_url`!");
+                return None;
+            }
+        };
 
         let mut iters = 0;
         while iters < MAX_ITERS {
             let client = awc::Client::new();
             let params = [("key", "bar")];
             let final_url =
-                "http://".to_owned() + &cache_server_url + "/cache/get?key=" + &key.to_string();
+                "http://".to_owned() + cache_server_url + "/cache/get?key=" + &key.to_string();
             let request = client.get(final_url).send_form(&params);
             let response = request.await;
 
@@ -67,12 +67,9 @@ impl<K: Sync + Send + Eq + Hash + ToString, V: Sync + Send + Clone + FromStr> Ca
             }
 
             let value = body
-                .map(|result| result.ok())
-                .flatten()
-                .map(|bytes| String::from_utf8(bytes.to_vec()).ok())
-                .flatten()
-                .map(|string| V::from_str(&string).ok())
-                .flatten();
+                .and_then(|result| result.ok())
+                .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+                .and_then(|string| V::from_str(&string).ok());
             if value.is_some() {
                 info!("Found value after {} iterations", iters);
                 return value;
@@ -101,7 +98,7 @@ mod tests {
         executor::block_on(cache.set("apples".to_string(), 5));
         executor::block_on(cache.set("strawberries".to_string(), -142));
         executor::block_on(cache.set("apples".to_string(), 3));
-        assert_eq!(cache.map.get(&"apples".to_string()).unwrap().to_owned(), 3);
+        assert_eq!(cache.map.get("apples").unwrap().to_owned(), 3);
     }
 
     #[test]
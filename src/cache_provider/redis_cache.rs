@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use redis::{aio::Connection, RedisError, RedisResult};
 use redis::{AsyncCommands, FromRedisValue, ToRedisArgs};
-use tracing::{error};
+use tracing::error;
 
 use crate::cache_provider::CacheProvider;
 
@@ -14,7 +14,7 @@ impl RedisCache {
         let client = redis::Client::open(url).unwrap();
         let con = client.get_async_connection().await.unwrap();
 
-        RedisCache { con: con }
+        RedisCache { con }
     }
 }
 
@@ -66,9 +66,8 @@ mod tests {
     use super::*;
 
     async fn get_cache() -> Box<dyn CacheProvider<String, i32>> {
-        let password;
-        match env::var("REDIS_PASSWORD") {
-            Ok(v) => password = v,
+        let password = match env::var("REDIS_PASSWORD") {
+            Ok(v) => v,
             Err(_) => panic!("$REDIS_PASSWORD is not set!"),
         };
 
@@ -0,0 +1,84 @@
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use std::net::IpAddr;
+
+// Proxies in front of the balancer (e.g. the ingress controller). Their
+// X-Forwarded-For entries are believed, anybody else could send any address.
+#[derive(Clone, Default)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpAddr>) -> TrustedProxies {
+        TrustedProxies(proxies)
+    }
+
+    // Walks the chain from the peer towards the client and stops at the first
+    // hop not added by a trusted proxy
+    fn resolve(&self, peer: IpAddr, forwarded_for: &[&str]) -> IpAddr {
+        let mut client = peer;
+        for hop in forwarded_for.iter().rev() {
+            if !self.0.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => client = hop,
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+// Address of the client, used for throttling and the audit log
+pub fn client_ip(request: &HttpRequest) -> Option<String> {
+    let peer = request.peer_addr()?.ip();
+    let proxies = match request.app_data::<Data<TrustedProxies>>() {
+        Some(proxies) => proxies,
+        None => return Some(peer.to_string()),
+    };
+    let forwarded_for: Vec<&str> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    Some(proxies.resolve(peer, &forwarded_for).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request(peer: &str, forwarded_for: &str) -> HttpRequest {
+        TestRequest::default()
+            .peer_addr(format!("{}:1234", peer).parse().unwrap())
+            .insert_header(("x-forwarded-for", forwarded_for))
+            .app_data(Data::new(TrustedProxies::new(vec![
+                "10.0.0.1".parse().unwrap(),
+                "10.0.0.2".parse().unwrap(),
+            ])))
+            .to_http_request()
+    }
+
+    #[test]
+    fn test_client_ip() {
+        // Clients cannot pick their address
+        let ip = client_ip(&request("203.0.113.7", "198.51.100.1"));
+        assert_eq!(ip.as_deref(), Some("203.0.113.7"));
+
+        let ip = client_ip(&request("10.0.0.1", "203.0.113.7"));
+        assert_eq!(ip.as_deref(), Some("203.0.113.7"));
+        // Entries before the ones added by trusted proxies are ignored
+        let ip = client_ip(&request("10.0.0.1", "198.51.100.1, 203.0.113.7, 10.0.0.2"));
+        assert_eq!(ip.as_deref(), Some("203.0.113.7"));
+        let ip = client_ip(&request("10.0.0.1", "garbage"));
+        assert_eq!(ip.as_deref(), Some("10.0.0.1"));
+
+        let request = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .to_http_request();
+        assert_eq!(client_ip(&request).as_deref(), Some("10.0.0.1"));
+    }
+}
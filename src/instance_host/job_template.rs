@@ -0,0 +1,274 @@
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::Api;
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+use std::str::FromStr;
+
+const DEFAULT_TEMPLATE: &str = include_str!("default_job.yaml");
+const PLACEHOLDERS: [&str; 3] = ["username", "instance_id", "port"];
+
+// Where the job template is read from: a YAML file, which is also how a
+// mounted ConfigMap is seen, or a key of a ConfigMap read through the API
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateSource {
+    File(String),
+    ConfigMap { name: String, key: String },
+}
+
+impl FromStr for TemplateSource {
+    type Err = String;
+
+    // `configmap:<name>/<key>` or a path
+    fn from_str(s: &str) -> Result<TemplateSource, String> {
+        match s.strip_prefix("configmap:") {
+            Some(reference) => match reference.split_once('/') {
+                Some((name, key)) if !name.is_empty() && !key.is_empty() => {
+                    Ok(TemplateSource::ConfigMap {
+                        name: name.to_owned(),
+                        key: key.to_owned(),
+                    })
+                }
+                _ => Err(format!("{} is not configmap:<name>/<key>", s)),
+            },
+            None => Ok(TemplateSource::File(s.to_owned())),
+        }
+    }
+}
+
+// Values substituted into the template for one instance
+pub struct TemplateValues<'a> {
+    pub username: &'a str,
+    pub instance_id: &'a str,
+    pub port: u16,
+}
+
+impl TemplateValues<'_> {
+    fn get(&self, placeholder: &str) -> Option<String> {
+        match placeholder {
+            "username" => Some(self.username.to_owned()),
+            "instance_id" => Some(self.instance_id.to_owned()),
+            "port" => Some(self.port.to_string()),
+            _ => None,
+        }
+    }
+}
+
+// Substitution happens on the parsed YAML, never on its text, so values
+// cannot change the structure of the job
+#[derive(Clone, Debug)]
+pub struct JobTemplate {
+    template: Value,
+}
+
+impl JobTemplate {
+    pub fn parse(yaml: &str) -> Result<JobTemplate, Box<dyn std::error::Error>> {
+        let template = JobTemplate {
+            template: serde_yaml::from_str(yaml)?,
+        };
+        template.validate()?;
+        Ok(template)
+    }
+
+    pub fn default_template() -> JobTemplate {
+        JobTemplate::parse(DEFAULT_TEMPLATE).unwrap()
+    }
+
+    // ConfigMaps are read from `namespace`, the one instances are started in
+    pub async fn load(
+        source: &TemplateSource,
+        namespace: Option<&str>,
+    ) -> Result<JobTemplate, Box<dyn std::error::Error>> {
+        let yaml = match source {
+            TemplateSource::File(path) => fs::read_to_string(path)?,
+            TemplateSource::ConfigMap { name, key } => {
+                let client = kube::Client::try_default().await?;
+                let config_maps: Api<ConfigMap> = match namespace {
+                    Some(namespace) => Api::namespaced(client, namespace),
+                    None => Api::default_namespaced(client),
+                };
+                let config_map = config_maps.get(name).await?;
+                match config_map.data.and_then(|mut data| data.remove(key)) {
+                    Some(yaml) => yaml,
+                    None => return Err(format!("ConfigMap {} has no key {}", name, key).into()),
+                }
+            }
+        };
+        JobTemplate::parse(&yaml)
+    }
+
+    // Renders with sample values so that mistakes show up at startup
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut unknown = Vec::new();
+        find_unknown_placeholders(&self.template, &mut unknown);
+        if !unknown.is_empty() {
+            return Err(
+                format!("Unknown job template placeholders: {}", unknown.join(", ")).into(),
+            );
+        }
+        let job = self.render(
+            "validation",
+            &TemplateValues {
+                username: "validation",
+                instance_id: "0",
+                port: 8080,
+            },
+        )?;
+        let containers = job
+            .spec
+            .and_then(|spec| spec.template.spec)
+            .map(|spec| spec.containers)
+            .unwrap_or_default();
+        if containers.is_empty() {
+            return Err("Job template has no containers".into());
+        }
+        Ok(())
+    }
+
+    pub fn render(
+        &self,
+        name: &str,
+        values: &TemplateValues,
+    ) -> Result<Job, Box<dyn std::error::Error>> {
+        let mut job: Job = serde_json::from_value(substitute(&self.template, values, false))?;
+        job.metadata.name = Some(name.to_owned());
+        Ok(job)
+    }
+}
+
+fn placeholder_regex() -> Regex {
+    Regex::new(r"\{\{\s*([a-z_]+)\s*\}\}").unwrap()
+}
+
+fn find_unknown_placeholders(value: &Value, unknown: &mut Vec<String>) {
+    match value {
+        Value::String(s) => {
+            for captures in placeholder_regex().captures_iter(s) {
+                let name = captures[1].to_owned();
+                if !PLACEHOLDERS.contains(&name.as_str()) && !unknown.contains(&name) {
+                    unknown.push(name);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                find_unknown_placeholders(value, unknown);
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values() {
+                find_unknown_placeholders(value, unknown);
+            }
+        }
+        _ => (),
+    }
+}
+
+// `port_field` is set for the values of `containerPort`, `targetPort`, ...
+fn substitute(value: &Value, values: &TemplateValues, port_field: bool) -> Value {
+    match value {
+        Value::String(s) => {
+            let regex = placeholder_regex();
+            // `containerPort: "{{port}}"` has to be a number, unlike the one in `args`
+            if let Some(captures) = regex.captures(s) {
+                if port_field && captures[0].len() == s.len() && &captures[1] == "port" {
+                    return Value::from(values.port);
+                }
+            }
+            let rendered = regex.replace_all(s, |captures: &regex::Captures| {
+                values.get(&captures[1]).unwrap_or_default()
+            });
+            Value::String(rendered.into_owned())
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| substitute(item, values, false))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let port_field = key.to_lowercase().ends_with("port");
+                    (key.clone(), substitute(value, values, port_field))
+                })
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: TemplateValues = TemplateValues {
+        username: "alice",
+        instance_id: "abc123",
+        port: 9000,
+    };
+
+    #[test]
+    fn test_default_template() {
+        let job = JobTemplate::default_template()
+            .render("alice", &VALUES)
+            .unwrap();
+        assert_eq!(job.metadata.name.as_deref(), Some("alice"));
+        let pod = job.spec.unwrap().template.spec.unwrap();
+        let container = &pod.containers[0];
+        assert_eq!(container.args.as_ref().unwrap()[2], "9000");
+        assert_eq!(container.ports.as_ref().unwrap()[0].container_port, 9000);
+    }
+
+    #[test]
+    fn test_substitution() {
+        let template = JobTemplate::parse(
+            "
+spec:
+  template:
+    metadata:
+      labels:
+        instance: '{{ instance_id }}'
+    spec:
+      containers:
+        - name: scene-host
+          image: scene-host:1.2
+          env:
+            - name: OWNER
+              value: 'user {{username}} on {{port}}'
+",
+        )
+        .unwrap();
+        let job = template.render("job", &VALUES).unwrap();
+        let spec = job.spec.unwrap().template;
+        assert_eq!(spec.metadata.unwrap().labels.unwrap()["instance"], "abc123");
+        let env = spec.spec.unwrap().containers[0].env.clone().unwrap();
+        assert_eq!(env[0].value.as_deref(), Some("user alice on 9000"));
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(JobTemplate::parse("spec: [").is_err());
+        // No containers
+        assert!(JobTemplate::parse("spec: {template: {}}").is_err());
+        let err = JobTemplate::parse(
+            "spec: {template: {spec: {containers: [{name: '{{user}}', image: a}]}}}",
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Unknown job template placeholders: user");
+
+        assert_eq!(
+            TemplateSource::from_str("configmap:lynx/job.yaml"),
+            Ok(TemplateSource::ConfigMap {
+                name: "lynx".to_string(),
+                key: "job.yaml".to_string()
+            })
+        );
+        assert!(TemplateSource::from_str("configmap:lynx").is_err());
+        assert_eq!(
+            TemplateSource::from_str("job.yaml"),
+            Ok(TemplateSource::File("job.yaml".to_string()))
+        );
+    }
+}
@@ -1,120 +1,651 @@
-use crate::instance_host::{Instance, InstanceHost};
+use crate::auth_manager::token;
+use crate::instance_host::job_template::{JobTemplate, TemplateValues};
+use crate::instance_host::readiness::ReadinessCheck;
+use crate::instance_host::{Instance, InstanceHost, InstanceStartError};
 
 use async_trait::async_trait;
-use k8s_openapi::api::{batch::v1::Job, core::v1::Pod};
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{Pod, Service, ServicePort, ServiceSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use k8s_openapi::NamespaceResourceScope;
 use kube::{
-    api::{Api, DeleteParams, ListParams, PostParams},
-    runtime::{conditions::is_pod_running, wait::await_condition},
+    api::{Api, DeleteParams, PostParams},
+    runtime::{watcher, WatchStreamExt},
+    Resource,
 };
-use tracing::info;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, warn};
 
-pub struct KubernetesHost {}
+const USER_LABEL: &str = "lynx/user";
+const INSTANCE_ID_LABEL: &str = "lynx/instance-id";
+// Label values are limited, the full username is kept here
+const USERNAME_ANNOTATION: &str = "lynx/username";
+// Names of jobs and services and label values
+const MAX_NAME_LENGTH: usize = 63;
+// Service names have to start with a letter (DNS-1035), job names may not
+const SERVICE_PREFIX: &str = "svc-";
+// Leaves room for the service prefix, so that service names never get cut off
+const MAX_JOB_NAME_LENGTH: usize = MAX_NAME_LENGTH - SERVICE_PREFIX.len();
+
+#[derive(Clone, Debug)]
+pub struct KubernetesConfig {
+    // `None` for the namespace of the balancer
+    pub namespace: Option<String>,
+    pub job_name_prefix: String,
+    // Name jobs after a hash of the username instead of the username itself,
+    // which is needed when usernames are not valid Kubernetes names
+    pub hash_job_names: bool,
+    // Added to jobs and their pods on top of the standard labels
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    // Port the scene host listens on, `{{port}}` in the template
+    pub port: u16,
+    // How long to wait for the pod to run
+    pub start_timeout: Duration,
+    // Create a service per instance and address the instance by its DNS name
+    pub create_services: bool,
+    pub cluster_domain: String,
+}
+
+impl KubernetesConfig {
+    pub fn job_name(&self, username: &str) -> String {
+        let name = self.job_name_prefix.clone() + username;
+        // Truncating long names could make them collide, they are hashed instead
+        if !self.hash_job_names && name.len() <= MAX_JOB_NAME_LENGTH {
+            return name;
+        }
+        let hash = hmac_sha256::Hash::hash(username.as_bytes());
+        let hex: String = hash[0..8].iter().map(|b| format!("{:02x}", b)).collect();
+        let prefix: String = self
+            .job_name_prefix
+            .chars()
+            .take(MAX_JOB_NAME_LENGTH - hex.len())
+            .collect();
+        prefix + &hex
+    }
+
+    // Standard labels first, so they cannot be overridden
+    fn labels(
+        &self,
+        job_name: &str,
+        username: &str,
+        instance_id: &str,
+    ) -> BTreeMap<String, String> {
+        let mut labels = self.labels.clone();
+        let standard = [
+            ("app.kubernetes.io/name", "lynx-scene-host"),
+            ("app.kubernetes.io/instance", job_name),
+            ("app.kubernetes.io/component", "instance"),
+            ("app.kubernetes.io/managed-by", "lynx-balancer"),
+            (INSTANCE_ID_LABEL, instance_id),
+        ];
+        for (key, value) in standard {
+            labels.insert(key.to_owned(), value.to_owned());
+        }
+        if is_label_value(username) {
+            labels.insert(USER_LABEL.to_owned(), username.to_owned());
+        }
+        labels
+    }
+
+    fn annotations(&self, username: &str) -> BTreeMap<String, String> {
+        let mut annotations = self.annotations.clone();
+        annotations.insert(USERNAME_ANNOTATION.to_owned(), username.to_owned());
+        annotations
+    }
+
+    fn api<K>(&self, client: kube::Client) -> Api<K>
+    where
+        K: Resource<Scope = NamespaceResourceScope>,
+        <K as Resource>::DynamicType: Default,
+    {
+        match &self.namespace {
+            Some(namespace) => Api::namespaced(client, namespace),
+            None => Api::default_namespaced(client),
+        }
+    }
+}
+
+fn service_name(job_name: &str) -> String {
+    let name: String = (SERVICE_PREFIX.to_owned() + job_name)
+        .chars()
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    name.trim_end_matches('-').to_owned()
+}
+
+// At most 63 alphanumerics, `-`, `_` or `.`, starting and ending with an alphanumeric
+fn is_label_value(value: &str) -> bool {
+    let alphanumeric = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    value.len() <= MAX_NAME_LENGTH
+        && alphanumeric(value.chars().next())
+        && alphanumeric(value.chars().last())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// `key=value` pairs from the command line
+pub fn parse_key_values(
+    pairs: &[String],
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let mut map = BTreeMap::new();
+    for pair in pairs {
+        match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                map.insert(key.trim().to_owned(), value.trim().to_owned());
+            }
+            _ => return Err(format!("{} is not key=value", pair).into()),
+        }
+    }
+    Ok(map)
+}
+
+pub struct KubernetesHost {
+    config: KubernetesConfig,
+    template: JobTemplate,
+    readiness: ReadinessCheck,
+}
 
 impl KubernetesHost {
-    pub fn new() -> KubernetesHost {
-        KubernetesHost {}
+    pub fn new(
+        config: KubernetesConfig,
+        template: JobTemplate,
+        readiness: ReadinessCheck,
+    ) -> KubernetesHost {
+        KubernetesHost {
+            config,
+            template,
+            readiness,
+        }
+    }
+
+    fn build_job(
+        &self,
+        username: &str,
+        instance_id: &str,
+    ) -> Result<Job, Box<dyn std::error::Error>> {
+        let job_name = self.config.job_name(username);
+        let values = TemplateValues {
+            username,
+            instance_id,
+            port: self.config.port,
+        };
+        let mut job = self.template.render(&job_name, &values)?;
+        let labels = self.config.labels(&job_name, username, instance_id);
+        let annotations = self.config.annotations(username);
+
+        let mut metadata = vec![&mut job.metadata];
+        if let Some(spec) = job.spec.as_mut() {
+            metadata.push(spec.template.metadata.get_or_insert_with(Default::default));
+        }
+        for metadata in metadata {
+            metadata
+                .labels
+                .get_or_insert_with(BTreeMap::new)
+                .extend(labels.clone());
+            metadata
+                .annotations
+                .get_or_insert_with(BTreeMap::new)
+                .extend(annotations.clone());
+        }
+        Ok(job)
     }
-    // TODO: we assume that job name = username. It probably should not be the case later. But it's ok for now.
+
     async fn create_job(
         &self,
-        username: String,
+        username: &str,
+        instance_id: &str,
         client: kube::Client,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<Job, Box<dyn std::error::Error>> {
         info!("Creating job for user: {}", username);
-        let jobs: Api<Job> = Api::default_namespaced(client);
-        let data = serde_json::from_value(serde_json::json!({
-            "apiVersion": "batch/v1",
-            "kind": "Job",
-            "metadata": {
-                "name": username,
+        let jobs: Api<Job> = self.config.api(client);
+        let data = self.build_job(username, instance_id)?;
+        Ok(jobs.create(&PostParams::default(), &data).await?)
+    }
+
+    // The service is owned by the job, so deleting the job deletes it too
+    fn build_service(&self, job: &Job, instance_id: &str) -> Service {
+        let job_name = job.metadata.name.clone().unwrap_or_default();
+        let owner = OwnerReference {
+            api_version: "batch/v1".to_string(),
+            kind: "Job".to_string(),
+            name: job_name.clone(),
+            uid: job.metadata.uid.clone().unwrap_or_default(),
+            controller: Some(true),
+            block_owner_deletion: Some(true),
+        };
+        Service {
+            metadata: ObjectMeta {
+                name: Some(service_name(&job_name)),
+                labels: job.metadata.labels.clone(),
+                annotations: job.metadata.annotations.clone(),
+                owner_references: Some(vec![owner]),
+                ..ObjectMeta::default()
             },
-            "spec": {
-                "template": {
-                    "metadata": {
-                        "name": "instance-dynamic-pod"
-                    },
-                    "spec": {
-                        "containers": [{
-                            "name": "scene-host",
-                            "image": "ghcr.io/project-lynx-coding-game/lynx-scene-host-python:latest",
-                            "args": ["main:app", "--port", "8080", "--host", "0.0.0.0", "--workers", "1"],
-                            "ports": [{"containerPort": 8080}],
-                            "env": [{
-                                "name": "LYNX_SCENE_GENERATOR_URL",
-                                "value":"http://lynx-scene-generator-service.lynx-scene-generator:8080/get_scene"
-                            }]
-                        }],
-                        "restartPolicy": "Never",
-                    }
-                }
-            }
-        }))?;
-        jobs.create(&PostParams::default(), &data).await?;
-        Ok(())
+            spec: Some(ServiceSpec {
+                type_: Some("ClusterIP".to_string()),
+                selector: Some(BTreeMap::from([(
+                    INSTANCE_ID_LABEL.to_owned(),
+                    instance_id.to_owned(),
+                )])),
+                ports: Some(vec![ServicePort {
+                    name: Some("http".to_string()),
+                    port: self.config.port.into(),
+                    target_port: Some(IntOrString::Int(self.config.port.into())),
+                    ..ServicePort::default()
+                }]),
+                ..ServiceSpec::default()
+            }),
+            ..Service::default()
+        }
     }
 
+    // Returns the DNS name of the service
+    async fn create_service(
+        &self,
+        job: &Job,
+        instance_id: &str,
+        client: kube::Client,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let namespace = match &self.config.namespace {
+            Some(namespace) => namespace.clone(),
+            None => client.default_namespace().to_owned(),
+        };
+        let services: Api<Service> = self.config.api(client);
+        let service = services
+            .create(
+                &PostParams::default(),
+                &self.build_service(job, instance_id),
+            )
+            .await?;
+        let name = service.metadata.name.unwrap_or_default();
+        Ok(format!(
+            "{}.{}.svc.{}",
+            name, namespace, self.config.cluster_domain
+        ))
+    }
+
+    // Everything after the job exists, which is deleted if this fails
+    async fn launch(
+        &self,
+        username: &str,
+        job: &Job,
+        instance_id: &str,
+        client: kube::Client,
+    ) -> Result<Instance, Box<dyn std::error::Error + Send + Sync>> {
+        let service = if self.config.create_services {
+            Some(
+                self.create_service(job, instance_id, client.clone())
+                    .await?,
+            )
+        } else {
+            None
+        };
+        let ip = self
+            .get_job_ip(username.to_owned(), instance_id.to_owned(), client)
+            .await?;
+        // The service keeps its address when the pod is rescheduled
+        let instance = Instance::new(service.unwrap_or(ip), self.config.port);
+        self.readiness.wait(&instance).await?;
+        Ok(instance)
+    }
+
+    // Watches the pods of the job until one runs, fails or the deadline passes
     async fn get_job_ip(
         &self,
         username: String,
+        instance_id: String,
         client: kube::Client,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let pods: Api<Pod> = Api::default_namespaced(client);
-        let label = format!("job-name={}", username);
-        let lp = ListParams::default().labels(&label);
-        let mut name = "".to_string();
-
-        while name == "" {
-            for p in pods.list(&lp).await? {
-                if p.metadata.deletion_timestamp.is_some() {
-                    // Pod is terminating
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let pods: Api<Pod> = self.config.api(client);
+        let label = format!("{}={}", INSTANCE_ID_LABEL, instance_id);
+        let deadline = Instant::now() + self.config.start_timeout;
+        // Watch errors, e.g. an expired watch, are retried until the deadline
+        let mut pod_events = watcher(pods, watcher::Config::default().labels(&label))
+            .default_backoff()
+            .applied_objects()
+            .boxed();
+        // Problems that may still resolve, reported if the deadline passes
+        let mut pending_problem = None;
+
+        loop {
+            let pod = match timeout_at(deadline, pod_events.try_next()).await {
+                Ok(Ok(Some(pod))) => pod,
+                Ok(Ok(None)) => return Err("Pod watch ended".into()),
+                Ok(Err(e)) => {
+                    warn!("Watching the pod of {} failed, retrying: {}", username, e);
                     continue;
                 }
-
-                name = p.metadata.name.unwrap();
-                info!("Pod name is: {}", name);
-                break;
+                Err(_) => {
+                    let error = pending_problem.unwrap_or(InstanceStartError::Timeout(
+                        self.config.start_timeout.as_secs(),
+                    ));
+                    return Err(error.into());
+                }
+            };
+            if pod.metadata.deletion_timestamp.is_some() {
+                // Pod is terminating
+                continue;
+            }
+            match pod_state(&pod) {
+                PodState::Running(ip) => {
+                    info!(
+                        "Pod created for {} was created at: {}:{}",
+                        username, ip, self.config.port
+                    );
+                    return Ok(ip);
+                }
+                PodState::Pending(problem) => pending_problem = problem,
+                PodState::Failed(error) => return Err(error.into()),
             }
         }
+    }
+}
+
+enum PodState {
+    Running(String),
+    // With a problem that may still resolve, e.g. while the cluster scales up
+    Pending(Option<InstanceStartError>),
+    Failed(InstanceStartError),
+}
 
-        let running = await_condition(pods.clone(), &name, is_pod_running());
-        let _ = tokio::time::timeout(std::time::Duration::from_secs(15), running).await?;
+fn pod_state(pod: &Pod) -> PodState {
+    let status = match &pod.status {
+        Some(status) => status,
+        None => return PodState::Pending(None),
+    };
+    match status.phase.as_deref() {
+        Some("Running") => {
+            if let Some(ip) = &status.pod_ip {
+                return PodState::Running(ip.clone());
+            }
+        }
+        Some("Failed") | Some("Succeeded") => {
+            let message = status
+                .message
+                .clone()
+                .or_else(|| status.reason.clone())
+                .unwrap_or_else(|| "Pod exited".to_string());
+            return PodState::Failed(InstanceStartError::Failed(message));
+        }
+        _ => (),
+    }
 
-        let p = pods.get(&name).await?;
-        let status = p.status.unwrap();
-        let ip = status.pod_ip.unwrap();
-        info!(
-            "Pod created for {} was created at: {}:{}",
-            username, ip, 8080
+    for container in status.container_statuses.iter().flatten() {
+        let waiting = match container
+            .state
+            .as_ref()
+            .and_then(|state| state.waiting.as_ref())
+        {
+            Some(waiting) => waiting,
+            None => continue,
+        };
+        let reason = waiting.reason.as_deref().unwrap_or_default();
+        let message = format!(
+            "{}: {}",
+            reason,
+            waiting.message.as_deref().unwrap_or_default()
         );
-        Ok(ip)
+        match reason {
+            "CrashLoopBackOff" => return PodState::Failed(InstanceStartError::CrashLoop(message)),
+            "ImagePullBackOff" | "InvalidImageName" | "ErrImageNeverPull" => {
+                return PodState::Failed(InstanceStartError::ImagePull(message))
+            }
+            "CreateContainerConfigError" | "CreateContainerError" => {
+                return PodState::Failed(InstanceStartError::Failed(message))
+            }
+            // Retried by the kubelet before it backs off
+            "ErrImagePull" => {
+                return PodState::Pending(Some(InstanceStartError::ImagePull(message)))
+            }
+            _ => (),
+        }
+    }
+
+    let unschedulable = status.conditions.iter().flatten().find(|condition| {
+        condition.type_ == "PodScheduled"
+            && condition.status == "False"
+            && condition.reason.as_deref() == Some("Unschedulable")
+    });
+    match unschedulable {
+        Some(condition) => PodState::Pending(Some(InstanceStartError::Unschedulable(
+            condition.message.clone().unwrap_or_default(),
+        ))),
+        None => PodState::Pending(None),
     }
 }
 
 #[async_trait]
 impl InstanceHost for KubernetesHost {
     async fn start_instance(
-        &mut self,
+        &self,
         username: String,
     ) -> Result<Instance, Box<dyn std::error::Error>> {
         let client = kube::Client::try_default().await?;
 
-        self.create_job(username.clone(), client.clone()).await?;
-        let ip: String = self.get_job_ip(username.clone(), client.clone()).await?;
-
-        let instance = Instance::new(ip, 8080);
+        let instance_id = token::generate_id();
+        let job = self
+            .create_job(&username, &instance_id, client.clone())
+            .await?;
+        let started = self
+            .launch(&username, &job, &instance_id, client.clone())
+            .await;
+        let instance = match started {
+            Ok(instance) => instance,
+            Err(e) => {
+                // Otherwise the next start fails because the job exists
+                let jobs: Api<Job> = self.config.api(client);
+                let name = self.config.job_name(&username);
+                if let Err(e) = jobs.delete(&name, &DeleteParams::background()).await {
+                    error!("Cannot delete job of failed instance {}: {}", username, e);
+                }
+                return Err(e);
+            }
+        };
 
         Ok(instance)
     }
 
-    async fn stop_instance(&mut self, username: String) -> Result<(), Box<dyn std::error::Error>> {
+    async fn stop_instance(&self, username: String) -> Result<(), Box<dyn std::error::Error>> {
         let client = kube::Client::try_default().await?;
-        let jobs: Api<Job> = Api::default_namespaced(client);
+        let jobs: Api<Job> = self.config.api(client);
 
         info!("Cleaning up job for: {}", username);
 
-        let name = username;
+        let name = self.config.job_name(&username);
         jobs.delete(&name, &DeleteParams::background()).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(status: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "alice-x7k2p"},
+            "status": status,
+        }))
+        .unwrap()
+    }
+
+    fn config() -> KubernetesConfig {
+        KubernetesConfig {
+            namespace: Some("lynx-instances".to_string()),
+            job_name_prefix: "lynx-".to_string(),
+            hash_job_names: false,
+            labels: parse_key_values(&["team=games".to_string()]).unwrap(),
+            annotations: BTreeMap::new(),
+            port: 8080,
+            start_timeout: Duration::from_secs(60),
+            create_services: true,
+            cluster_domain: "cluster.local".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_job_name() {
+        let mut config = config();
+        assert_eq!(config.job_name("alice"), "lynx-alice");
+        config.hash_job_names = true;
+        let name = config.job_name("Alice Smith");
+        assert_eq!(name.len(), "lynx-".len() + 16);
+        assert_eq!(name, config.job_name("Alice Smith"));
+        assert_ne!(name, config.job_name("alice"));
+
+        // Long names are hashed rather than cut off
+        config.hash_job_names = false;
+        let long = "a".repeat(57) + "-b";
+        let name = config.job_name(&long);
+        assert_eq!(name.len(), "lynx-".len() + 16);
+        assert_ne!(name, config.job_name(&("a".repeat(57) + "-c")));
+        config.job_name_prefix = "x".repeat(70);
+        let name = config.job_name("alice");
+        assert_eq!(name.len(), MAX_JOB_NAME_LENGTH);
+        assert!(name.starts_with("xxx"));
+        assert!(!name.ends_with('-'));
+
+        assert!(parse_key_values(&["team".to_string()]).is_err());
+        assert!(is_label_value("alice.smith-2"));
+        assert!(!is_label_value("alice smith"));
+        assert!(!is_label_value("-alice"));
+        assert!(!is_label_value(""));
+    }
+
+    #[test]
+    fn test_build_job() {
+        let host = KubernetesHost::new(
+            config(),
+            JobTemplate::default_template(),
+            ReadinessCheck {
+                path: "/".to_string(),
+                timeout: Duration::from_secs(1),
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+            },
+        );
+        let job = host.build_job("alice", "abc123").unwrap();
+        assert_eq!(job.metadata.name.as_deref(), Some("lynx-alice"));
+        let pod_metadata = job.spec.unwrap().template.metadata.unwrap();
+        for metadata in [job.metadata, pod_metadata] {
+            let labels = metadata.labels.unwrap();
+            assert_eq!(labels[USER_LABEL], "alice");
+            assert_eq!(labels[INSTANCE_ID_LABEL], "abc123");
+            assert_eq!(labels["app.kubernetes.io/instance"], "lynx-alice");
+            assert_eq!(labels["team"], "games");
+            assert_eq!(metadata.annotations.unwrap()[USERNAME_ANNOTATION], "alice");
+        }
+    }
+
+    #[test]
+    fn test_service_name() {
+        // Job names may start with a digit, service names may not
+        let mut config = config();
+        config.job_name_prefix = String::new();
+        assert_eq!(service_name(&config.job_name("007")), "svc-007");
+        config.hash_job_names = true;
+        assert!(service_name(&config.job_name("alice")).starts_with(SERVICE_PREFIX));
+
+        let name = service_name(&("a".repeat(58) + "-bcde"));
+        assert_eq!(name, "svc-".to_owned() + &"a".repeat(58));
+    }
+
+    #[test]
+    fn test_build_service() {
+        let host = KubernetesHost::new(
+            config(),
+            JobTemplate::default_template(),
+            ReadinessCheck {
+                path: "/".to_string(),
+                timeout: Duration::from_secs(1),
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+            },
+        );
+        let mut job = host.build_job("alice", "abc123").unwrap();
+        job.metadata.uid = Some("0b5e-uid".to_string());
+        let service = host.build_service(&job, "abc123");
+
+        assert_eq!(service.metadata.name.as_deref(), Some("svc-lynx-alice"));
+        let owner = &service.metadata.owner_references.unwrap()[0];
+        assert_eq!(
+            (owner.kind.as_str(), owner.uid.as_str()),
+            ("Job", "0b5e-uid")
+        );
+        let spec = service.spec.unwrap();
+        assert_eq!(spec.selector.unwrap()[INSTANCE_ID_LABEL], "abc123");
+        let port = &spec.ports.unwrap()[0];
+        assert_eq!(port.port, 8080);
+        assert_eq!(port.target_port, Some(IntOrString::Int(8080)));
+    }
+
+    fn waiting(reason: &str) -> Pod {
+        pod(serde_json::json!({
+            "phase": "Pending",
+            "containerStatuses": [{
+                "name": "scene-host",
+                "image": "scene-host",
+                "imageID": "",
+                "ready": false,
+                "restartCount": 0,
+                "state": {"waiting": {"reason": reason, "message": "details"}},
+            }],
+        }))
+    }
+
+    #[test]
+    fn test_pod_state() {
+        let running = pod(serde_json::json!({"phase": "Running", "podIP": "10.0.0.7"}));
+        assert!(matches!(pod_state(&running), PodState::Running(ip) if ip == "10.0.0.7"));
+        assert!(matches!(
+            pod_state(&pod(serde_json::json!({"phase": "Pending"}))),
+            PodState::Pending(None)
+        ));
+
+        let unschedulable = pod(serde_json::json!({
+            "phase": "Pending",
+            "conditions": [{
+                "type": "PodScheduled",
+                "status": "False",
+                "reason": "Unschedulable",
+                "message": "0/3 nodes are available",
+            }],
+        }));
+        assert!(matches!(
+            pod_state(&unschedulable),
+            PodState::Pending(Some(InstanceStartError::Unschedulable(message)))
+                if message == "0/3 nodes are available"
+        ));
+
+        assert!(matches!(
+            pod_state(&waiting("ErrImagePull")),
+            PodState::Pending(Some(InstanceStartError::ImagePull(_)))
+        ));
+        assert!(matches!(
+            pod_state(&waiting("ImagePullBackOff")),
+            PodState::Failed(InstanceStartError::ImagePull(_))
+        ));
+        assert!(matches!(
+            pod_state(&waiting("CrashLoopBackOff")),
+            PodState::Failed(InstanceStartError::CrashLoop(message))
+                if message == "CrashLoopBackOff: details"
+        ));
+        assert!(matches!(
+            pod_state(&waiting("ContainerCreating")),
+            PodState::Pending(None)
+        ));
+
+        let failed = pod(serde_json::json!({"phase": "Failed", "reason": "Evicted"}));
+        assert!(matches!(
+            pod_state(&failed),
+            PodState::Failed(InstanceStartError::Failed(message)) if message == "Evicted"
+        ));
+    }
+}
@@ -1,62 +1,189 @@
+use crate::instance_host::readiness::ReadinessCheck;
 use crate::instance_host::{Instance, InstanceHost};
 
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::TcpListener;
 use std::process::{Child, Command};
+use std::sync::Mutex;
 
+const PORTS: std::ops::Range<u16> = 8000..9000;
+
+// Starts run concurrently, so everything is tracked under one lock
+#[derive(Default)]
+struct Processes {
+    running: HashMap<String, (Child, u16)>,
+    // Users with a start in progress, one at a time per user
+    starting: HashSet<String>,
+    // Ports of running and starting instances
+    ports: HashSet<u16>,
+}
 
 pub struct LocalHost {
-    processes: HashMap<String, Child>,
+    processes: Mutex<Processes>,
     app_directory: String, // we assume it is a FastAPI app (lynx-scene-host), uvicorn required
+    readiness: ReadinessCheck,
 }
 
 impl LocalHost {
-    pub fn new(app_directory: String) -> LocalHost {
+    pub fn new(app_directory: String, readiness: ReadinessCheck) -> LocalHost {
         LocalHost {
-            processes: HashMap::new(),
+            processes: Mutex::new(Processes::default()),
             app_directory,
+            readiness,
         }
     }
-}
 
-fn get_available_port() -> Option<u16> {
-    (8000..9000).find(|port| port_is_available(*port))
+    // Stops a previous instance of the user and reserves a port for the new one
+    fn reserve(&self, username: &str) -> Result<PendingStart<'_>, Box<dyn std::error::Error>> {
+        let mut processes = self.processes.lock().unwrap();
+        if processes.starting.contains(username) {
+            return Err("Instance is already starting".into());
+        }
+        if let Some((mut child, port)) = processes.running.remove(username) {
+            let _ = child.kill();
+            let _ = child.wait();
+            processes.ports.remove(&port);
+        }
+        // Other programs may use ports as well, those are only seen by binding
+        let port = PORTS
+            .clone()
+            .find(|port| !processes.ports.contains(port) && port_is_available(*port))
+            .ok_or("No available ports")?;
+        processes.ports.insert(port);
+        processes.starting.insert(username.to_owned());
+        Ok(PendingStart {
+            host: self,
+            username: username.to_owned(),
+            port,
+            child: None,
+            done: false,
+        })
+    }
 }
 
 fn port_is_available(port: u16) -> bool {
-    match TcpListener::bind(("0.0.0.0", port)) {
-        Ok(_) => true,
-        Err(_) => false,
+    TcpListener::bind(("0.0.0.0", port)).is_ok()
+}
+
+// A start in progress, undone when dropped before `finish`, which also
+// covers requests cancelled while waiting for the instance
+struct PendingStart<'a> {
+    host: &'a LocalHost,
+    username: String,
+    port: u16,
+    child: Option<Child>,
+    done: bool,
+}
+
+impl PendingStart<'_> {
+    fn finish(mut self) {
+        let mut processes = self.host.processes.lock().unwrap();
+        processes.starting.remove(&self.username);
+        if let Some(child) = self.child.take() {
+            processes
+                .running
+                .insert(self.username.clone(), (child, self.port));
+        }
+        self.done = true;
+    }
+}
+
+impl Drop for PendingStart<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Some(mut child) = self.child.take() {
+            // The process may have exited already
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let mut processes = self.host.processes.lock().unwrap();
+        processes.starting.remove(&self.username);
+        processes.ports.remove(&self.port);
     }
 }
 
 #[async_trait]
 impl InstanceHost for LocalHost {
     async fn start_instance(
-        &mut self,
+        &self,
         username: String,
     ) -> Result<Instance, Box<dyn std::error::Error>> {
-        let port = get_available_port().expect("no available ports");
+        let mut pending = self.reserve(&username)?;
         let child = Command::new("sh")
             .arg("-c")
             .arg(format!(
                 "cd {} && uvicorn main:app --port {} --host 0.0.0.0",
-                self.app_directory, port
+                self.app_directory, pending.port
             ))
-            .spawn()
-            .expect("failed to execute process");
-        self.processes.insert(username.clone(), child);
-        let instance = Instance::new("0.0.0.0".to_string(), port);
+            .spawn()?;
+        pending.child = Some(child);
+        let instance = Instance::new("0.0.0.0".to_string(), pending.port);
+        match self.readiness.wait(&instance).await {
+            Ok(()) => pending.finish(),
+            Err(e) => return Err(e),
+        }
         Ok(instance)
     }
 
-    async fn stop_instance(&mut self, username: String) -> Result<(), Box<dyn std::error::Error>> {
-        let child = self
-            .processes
-            .get_mut(&username)
-            .expect("No process running");
-        child.kill().expect("cannot kill"); // TODO: change it to graceful exit, then kill if cannot exit gracefully
+    async fn stop_instance(&self, username: String) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut child, port) = {
+            let mut processes = self.processes.lock().unwrap();
+            if processes.starting.contains(&username) {
+                return Err("Instance is still starting".into());
+            }
+            match processes.running.remove(&username) {
+                Some(process) => process,
+                None => return Err("No process running".into()),
+            }
+        };
+        child.kill()?; // TODO: change it to graceful exit, then kill if cannot exit gracefully
+        child.wait()?;
+        self.processes.lock().unwrap().ports.remove(&port);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn host() -> LocalHost {
+        LocalHost::new(
+            "/nonexistent".to_string(),
+            ReadinessCheck {
+                path: "/".to_string(),
+                timeout: Duration::from_millis(10),
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+            },
+        )
+    }
+
+    #[test]
+    fn test_reserve() {
+        let host = host();
+        let alice = host.reserve("alice").unwrap();
+        assert!(host.reserve("alice").is_err());
+        let bob = host.reserve("bob").unwrap();
+        assert_ne!(alice.port, bob.port);
+
+        // An unfinished start gives its port back
+        let port = alice.port;
+        drop(alice);
+        assert!(!host.processes.lock().unwrap().ports.contains(&port));
+        assert!(host.reserve("alice").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stop_while_starting() {
+        let host = host();
+        let pending = host.reserve("alice").unwrap();
+        assert!(host.stop_instance("alice".to_string()).await.is_err());
+        pending.finish();
+        assert!(host.processes.lock().unwrap().starting.is_empty());
+    }
+}
@@ -1,8 +1,12 @@
+pub mod job_template;
 pub mod kubernetes_host;
 pub mod local_host;
+pub mod readiness;
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Instance {
@@ -20,11 +24,52 @@ impl Instance {
     }
 }
 
+// Why an instance did not come up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstanceStartError {
+    // Seconds waited for the pod to run
+    Timeout(u64),
+    Unschedulable(String),
+    ImagePull(String),
+    CrashLoop(String),
+    Failed(String),
+    // Time waited for the instance to answer
+    NotReady(Duration),
+}
+
+impl fmt::Display for InstanceStartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstanceStartError::Timeout(secs) => {
+                write!(f, "Instance did not start within {} seconds", secs)
+            }
+            InstanceStartError::Unschedulable(message) => {
+                write!(f, "Instance cannot be scheduled: {}", message)
+            }
+            InstanceStartError::ImagePull(message) => {
+                write!(f, "Instance image cannot be pulled: {}", message)
+            }
+            InstanceStartError::CrashLoop(message) => {
+                write!(f, "Instance keeps crashing: {}", message)
+            }
+            InstanceStartError::Failed(message) => write!(f, "Instance failed: {}", message),
+            InstanceStartError::NotReady(timeout) => {
+                write!(f, "Instance did not answer within {:?}", timeout)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstanceStartError {}
+
+// Shared between requests without holding the `AppState` lock, as starting
+// an instance takes a while, so implementations synchronize internally
 #[async_trait]
 pub trait InstanceHost {
+    // Only returns once the instance passed its `ReadinessCheck`
     async fn start_instance(
-        &mut self,
+        &self,
         username: String,
     ) -> Result<Instance, Box<dyn std::error::Error>>;
-    async fn stop_instance(&mut self, username: String) -> Result<(), Box<dyn std::error::Error>>;
+    async fn stop_instance(&self, username: String) -> Result<(), Box<dyn std::error::Error>>;
 }
@@ -0,0 +1,111 @@
+use crate::instance_host::{Instance, InstanceStartError};
+
+use hyper::{Client, Uri};
+use std::time::Duration;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::info;
+
+// A freshly started scene host takes a moment before it listens, so
+// `start_instance` polls `path` until the instance answers with anything
+// but a server error, waiting `initial_delay` between attempts and doubling
+// it up to `max_delay`, for `timeout` in total.
+#[derive(Clone, Debug)]
+pub struct ReadinessCheck {
+    pub path: String,
+    pub timeout: Duration,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReadinessCheck {
+    pub async fn wait(
+        &self,
+        instance: &Instance,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let uri: Uri = format!("http://{}{}", instance.get_url_with_port(), self.path).parse()?;
+        let client = Client::new();
+        let deadline = Instant::now() + self.timeout;
+        let mut delay = self.initial_delay;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if let Ok(Ok(response)) = timeout(remaining, client.get(uri.clone())).await {
+                if !response.status().is_server_error() {
+                    info!("{} is ready after {} attempts", uri, attempts);
+                    return Ok(());
+                }
+            }
+
+            if Instant::now() + delay >= deadline {
+                return Err(InstanceStartError::NotReady(self.timeout).into());
+            }
+            sleep(delay).await;
+            delay = (delay * 2).min(self.max_delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn check(timeout: Duration) -> ReadinessCheck {
+        ReadinessCheck {
+            path: "/health".to_string(),
+            timeout,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(40),
+        }
+    }
+
+    // Answers the given statuses, one connection each
+    fn serve(statuses: Vec<u16>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_ready_after_server_errors() {
+        let port = serve(vec![503, 503, 404]);
+        let instance = Instance::new("127.0.0.1".to_string(), port);
+        check(Duration::from_secs(5)).wait(&instance).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_not_ready() {
+        // Nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let instance = Instance::new("127.0.0.1".to_string(), port);
+        let err = check(Duration::from_millis(100))
+            .wait(&instance)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InstanceStartError>(),
+            Some(&InstanceStartError::NotReady(Duration::from_millis(100)))
+        );
+        assert_eq!(err.to_string(), "Instance did not answer within 100ms");
+    }
+}
@@ -1,38 +1,77 @@
+mod audit;
 mod auth_manager;
 mod cache_provider;
+mod client_ip;
 mod instance_host;
 mod routes;
+mod session;
 
+use crate::audit::file_audit_log::FileAuditLog;
+use crate::audit::no_audit_log::NoAuditLog;
+use crate::audit::redis_audit_log::RedisAuditLog;
+use crate::audit::AuditLog;
+use crate::auth_manager::local_auth_manager::LocalAuthManager;
+use crate::auth_manager::login_throttle::LoginThrottle;
+use crate::auth_manager::oidc::{OidcConfig, OidcProvider};
 use crate::auth_manager::redis_auth_manager::RedisAuthManager;
+use crate::auth_manager::role::Role;
+use crate::auth_manager::signing_keys::SigningKeys;
+use crate::auth_manager::sqlite_auth_manager::SqliteAuthManager;
+use crate::auth_manager::token::TokenConfig;
+use crate::auth_manager::username_policy::UsernamePolicy;
 use crate::auth_manager::AuthManager;
-use crate::instance_host::kubernetes_host::KubernetesHost;
+use crate::client_ip::TrustedProxies;
+use crate::instance_host::job_template::{JobTemplate, TemplateSource};
+use crate::instance_host::kubernetes_host::{self, KubernetesConfig, KubernetesHost};
 use crate::instance_host::local_host::LocalHost;
+use crate::instance_host::readiness::ReadinessCheck;
 use crate::instance_host::InstanceHost;
-use crate::routes::{auth, cache_server, instance_server, proxy_server};
+use crate::routes::{admin, auth, cache_server, instance_server, proxy_server};
+use crate::session::backend::SessionBackend;
+use crate::session::csrf::{self, CsrfProtection};
+use crate::session::key_rotation::KeyRotation;
+use crate::session::local_session_store::LocalSessionStore;
+use crate::session::redis_session_store::RedisSessionStore;
+use crate::session::{ServerSessionStore, SessionConfig};
 
-use actix_session::config::{BrowserSession, CookieContentSecurity};
-use actix_session::storage::CookieSessionStore;
-use actix_session::SessionMiddleware;
-use actix_web::cookie::{Key, SameSite};
+use actix_web::cookie::SameSite;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
 use cache_provider::local_cache::LocalCache;
 use cache_provider::redis_cache::RedisCache;
 use cache_provider::CacheProvider;
-use clap::{Parser, ValueEnum};
+use clap::{ArgAction, Parser, ValueEnum};
 use futures::lock::Mutex;
-use tracing::info;
+use jwt_simple::prelude::Duration;
+use regex::Regex;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{error, info, warn};
 
 pub struct AppState {
     // It's quite complex but Sync and Send traits mean
     // that the impl can be moved across threads
     // https://doc.rust-lang.org/nomicon/send-and-sync.html
-    instance_host: Box<dyn InstanceHost + Sync + Send>,
+    instance_host: Arc<dyn InstanceHost + Sync + Send>,
     auth_manager: Box<dyn AuthManager + Sync + Send>,
+    audit_log: Box<dyn AuditLog + Sync + Send>,
+    signing_keys: Option<Arc<SigningKeys>>,
+    oidc_provider: Option<Arc<OidcProvider>>,
+    // `None` with cookie sessions
+    session_store: Option<Arc<dyn ServerSessionStore>>,
     url_cache: Box<dyn CacheProvider<String, String> + Sync + Send>,
     use_cache_query: bool,
+    login_throttle: LoginThrottle,
+    username_policy: UsernamePolicy,
+    guest_lifetime: u64,
+    max_guests: usize,
 }
 
+// Seconds between looking for expired guests
+const GUEST_CLEANUP_INTERVAL: u64 = 60;
+
 /// Lynx balancer
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -73,6 +112,220 @@ struct Args {
 
     #[arg(long, default_value = "")]
     app_path: String,
+
+    /// Job template for instances on kubernetes, a YAML file or configmap:<name>/<key>
+    /// in the instance namespace.
+    /// Defaults to the built-in scene host job
+    #[arg(long)]
+    job_template: Option<TemplateSource>,
+
+    /// Namespace instances are started in on kubernetes, defaults to the one of the balancer
+    #[arg(long)]
+    namespace: Option<String>,
+
+    /// Prepended to the names of instance jobs
+    #[arg(long, default_value = "")]
+    job_name_prefix: String,
+
+    /// Name instance jobs after a hash of the username, for usernames that are not valid job names
+    #[arg(long)]
+    hash_job_names: bool,
+
+    /// Extra key=value labels of instance jobs and pods
+    #[arg(long, value_delimiter = ',')]
+    instance_labels: Vec<String>,
+
+    /// Extra key=value annotations of instance jobs and pods
+    #[arg(long, value_delimiter = ',')]
+    instance_annotations: Vec<String>,
+
+    /// Create a ClusterIP service per instance and proxy to its DNS name instead of the pod IP
+    #[arg(long)]
+    instance_services: bool,
+
+    /// DNS domain of the cluster, used to address instance services
+    #[arg(long, default_value = "cluster.local")]
+    cluster_domain: String,
+
+    /// Port the scene host listens on inside an instance on kubernetes
+    #[arg(long, default_value_t = 8080)]
+    instance_port: u16,
+
+    /// Seconds to wait for an instance pod to run before giving up
+    #[arg(long, default_value_t = 120)]
+    instance_start_timeout: u64,
+
+    /// Path polled on a started instance until it answers without a server error
+    #[arg(long, default_value = "/")]
+    readiness_path: String,
+
+    /// Seconds to wait for a started instance to answer on readiness_path
+    #[arg(long, default_value_t = 60)]
+    readiness_timeout: u64,
+
+    /// Where users are stored: redis-auth, local-auth or sqlite:<path>
+    #[arg(long, num_args = 0..=1, default_value_t = Auth::Redis)]
+    auth: Auth,
+
+    /// JSON file the users are persisted to when using local-auth
+    #[arg(long)]
+    auth_file: Option<String>,
+
+    /// Where audit events (logins, registrations, instance starts, ...) are recorded.
+    /// Defaults to redis-audit when Redis is used for users, sessions or the cache, no-audit otherwise
+    #[arg(long, value_enum)]
+    audit: Option<Audit>,
+
+    /// JSON-lines file audit events are appended to when using file-audit,
+    /// has to be on a writable volume kept across restarts
+    #[arg(long, required_if_eq("audit", "file-audit"))]
+    audit_file: Option<String>,
+
+    /// Issuer (`iss`) put into and required from every token
+    #[arg(long, default_value = "lynx-balancer")]
+    token_issuer: String,
+
+    /// Audience (`aud`) put into and required from every token
+    #[arg(long, default_value = "lynx")]
+    token_audience: String,
+
+    /// Tolerated clock skew in seconds when validating tokens
+    #[arg(long, default_value_t = 60)]
+    token_leeway: u64,
+
+    /// Token lifetime in seconds
+    #[arg(long, default_value_t = 7200)]
+    token_lifetime: u64,
+
+    /// For how many seconds after expiring a token can still be refreshed
+    #[arg(long, default_value_t = 600)]
+    token_refresh_grace: u64,
+
+    /// Maximum session length in seconds, after which the user has to log in again
+    #[arg(long, default_value_t = 86400)]
+    max_session_length: u64,
+
+    /// Issuer URL of an OpenID Connect provider users can log in with
+    #[arg(long, requires_all = ["oidc_client_id", "oidc_redirect_url"])]
+    oidc_issuer: Option<String>,
+
+    #[arg(long)]
+    oidc_client_id: Option<String>,
+
+    /// Only needed if the provider does not treat the balancer as public client
+    #[arg(long)]
+    oidc_client_secret: Option<String>,
+
+    /// Where the provider sends users back to, i.e. `<balancer URL>/auth/oidc/callback`
+    #[arg(long)]
+    oidc_redirect_url: Option<String>,
+
+    #[arg(long, default_value = "openid profile")]
+    oidc_scopes: String,
+
+    /// ID token claim new users are named after
+    #[arg(long, default_value = "preferred_username")]
+    oidc_username_claim: String,
+
+    /// ES256 or RS256 private key (PEM) to sign tokens with instead of per-user
+    /// HS256 keys. The file stem is used as key ID.
+    #[arg(long)]
+    signing_key: Option<String>,
+
+    /// Further keys (PEM, private or public) tokens are accepted from and which
+    /// are published as JWKS, e.g. the previous or the next signing key
+    #[arg(long, value_delimiter = ',', requires = "signing_key")]
+    verification_keys: Vec<String>,
+
+    /// Failed logins per user or IP before further attempts are delayed
+    #[arg(long, default_value_t = 3)]
+    login_free_attempts: u32,
+
+    /// Longest delay in seconds between failed logins, i.e. the lockout duration
+    #[arg(long, default_value_t = 900)]
+    login_max_delay: u64,
+
+    /// Regex new usernames have to match, on top of being valid DNS-1123 labels
+    #[arg(long)]
+    username_pattern: Option<String>,
+
+    #[arg(long, default_value_t = 3)]
+    username_min_length: usize,
+
+    /// Capped at 63, the limit for Kubernetes names
+    #[arg(long, default_value_t = 32)]
+    username_max_length: usize,
+
+    /// Usernames nobody can register
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "admin,root,system,guest,guests,audit,lynx"
+    )]
+    reserved_usernames: Vec<String>,
+
+    /// Addresses of proxies in front of the balancer, whose X-Forwarded-For header is trusted
+    /// for the client address. Without them the address of the connection is used
+    #[arg(long, value_delimiter = ',')]
+    trusted_proxies: Vec<IpAddr>,
+
+    /// Users which are granted the admin role on startup
+    #[arg(long, value_delimiter = ',')]
+    admin_users: Vec<String>,
+
+    /// Seconds until a guest and its instance are deleted, unless upgraded to an account
+    #[arg(long, default_value_t = 3600)]
+    guest_lifetime: u64,
+
+    /// Maximum number of guests at the same time, 0 disables guest sessions
+    #[arg(long, default_value_t = 50)]
+    max_guests: usize,
+
+    /// File the session cookie key is read from, a new key is generated if it does not exist.
+    /// With several replicas all of them need the same key, e.g. set session_key instead
+    #[arg(long, default_value = "session.key")]
+    session_key_file: String,
+
+    /// Base64 encoded session cookie key (at least 64 bytes), takes precedence over the key file
+    #[arg(long, env = "LYNX_SESSION_KEY", hide_env_values = true)]
+    session_key: Option<String>,
+
+    /// Previous session cookie keys, cookies encrypted with them are re-encrypted with the current key
+    #[arg(
+        long,
+        env = "LYNX_PREVIOUS_SESSION_KEYS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    previous_session_keys: Vec<String>,
+
+    /// Name of the session cookie
+    #[arg(long, default_value = "session-cookie")]
+    session_cookie_name: String,
+
+    /// Only send the session cookie over HTTPS
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    session_cookie_secure: bool,
+
+    /// SameSite attribute of the session cookie
+    #[arg(long, value_enum, default_value_t = CookieSameSite::Strict)]
+    session_cookie_same_site: CookieSameSite,
+
+    /// Origins besides the balancer itself which may send cookie-authenticated POST requests
+    #[arg(long, value_delimiter = ',')]
+    allowed_origins: Vec<String>,
+
+    /// Where session state is kept, server-side sessions can be listed and terminated by admins
+    #[arg(long, value_enum, default_value_t = SessionStore::Cookie)]
+    session_store: SessionStore,
+
+    /// Session cookie lifetime in seconds, by default the cookie is deleted when the browser closes
+    #[arg(long)]
+    session_lifetime: Option<u64>,
+
+    /// Hash any passwords still stored as plaintext before starting (redis-auth only)
+    #[arg(long, default_value_t = false)]
+    migrate_passwords: bool,
 }
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
@@ -81,14 +334,28 @@ enum Cache {
     LocalCache,
 }
 
-fn session_middleware() -> SessionMiddleware<CookieSessionStore> {
-    SessionMiddleware::builder(CookieSessionStore::default(), Key::from(&[0; 64]))
-        .cookie_name(String::from("session-cookie"))
-        .session_lifecycle(BrowserSession::default())
-        .cookie_same_site(SameSite::Strict)
-        .cookie_content_security(CookieContentSecurity::Private)
-        .cookie_http_only(true)
-        .build()
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum SessionStore {
+    Cookie,
+    Redis,
+    Local,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum CookieSameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(same_site: CookieSameSite) -> SameSite {
+        match same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
 }
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
@@ -97,28 +364,250 @@ enum Host {
     Kubernetes,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Auth {
+    Redis,
+    Local,
+    // Path of the database file
+    Sqlite(String),
+}
+
+impl fmt::Display for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Auth::Redis => write!(f, "redis-auth"),
+            Auth::Local => write!(f, "local-auth"),
+            Auth::Sqlite(path) => write!(f, "sqlite:{}", path),
+        }
+    }
+}
+
+impl FromStr for Auth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Auth, String> {
+        match s {
+            "redis-auth" => Ok(Auth::Redis),
+            "local-auth" => Ok(Auth::Local),
+            _ => match s.strip_prefix("sqlite:") {
+                Some(path) if !path.is_empty() => Ok(Auth::Sqlite(path.to_owned())),
+                _ => Err(format!(
+                    "{} is not one of redis-auth, local-auth or sqlite:<path>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum Audit {
+    #[value(name = "file-audit")]
+    File,
+    #[value(name = "redis-audit")]
+    Redis,
+    #[value(name = "no-audit")]
+    Disabled,
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
-    if args.host == Host::Localhost && args.app_path == "" {
+    if args.host == Host::Localhost && args.app_path.is_empty() {
         panic!("app_path must be specified when host is local host");
     }
 
+    let username_pattern = args
+        .username_pattern
+        .map(|pattern| Regex::new(&pattern).expect("username_pattern is not a valid regex"));
+
     let subscriber = tracing_subscriber::FmtSubscriber::new();
     match tracing::subscriber::set_global_default(subscriber) {
         Ok(_) => (),
         Err(_) => println!("ERROR tracing could not be enabled!"),
     }
 
+    if args.session_cookie_same_site == CookieSameSite::None && !args.session_cookie_secure {
+        panic!("session_cookie_secure must be set when session_cookie_same_site is none");
+    }
+    let session_store: Option<Arc<dyn ServerSessionStore>> = match args.session_store {
+        SessionStore::Cookie => None,
+        SessionStore::Redis => Some(Arc::new(
+            RedisSessionStore::new(args.redis_url.clone()).await,
+        )),
+        SessionStore::Local => Some(Arc::new(LocalSessionStore::new())),
+    };
+    let key = match session::load_key(args.session_key.as_deref(), &args.session_key_file) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Cannot load session key: {}", e);
+            return Err(std::io::Error::other(e.to_string()));
+        }
+    };
+    let session_config = SessionConfig {
+        key,
+        previous_keys: args
+            .previous_session_keys
+            .iter()
+            .map(|key| session::parse_key(key).expect("Cannot parse previous session key"))
+            .collect(),
+        cookie_name: args.session_cookie_name,
+        secure: args.session_cookie_secure,
+        same_site: args.session_cookie_same_site.into(),
+        lifetime: args.session_lifetime,
+        backend: match &session_store {
+            Some(store) => SessionBackend::Server(store.clone()),
+            None => SessionBackend::Cookie,
+        },
+        allowed_origins: args
+            .allowed_origins
+            .iter()
+            .map(|origin| csrf::parse_origin(origin).expect("Cannot parse allowed origin"))
+            .collect(),
+    };
+
+    info!("Preparing `auth_manager`");
+    let signing_keys = args.signing_key.map(|path| {
+        let keys =
+            SigningKeys::load(&path, &args.verification_keys).expect("Cannot load signing keys");
+        info!("Signing tokens with {}, verifying with {:?}", path, keys);
+        Arc::new(keys)
+    });
+    let token_config = TokenConfig {
+        issuer: args.token_issuer,
+        audience: args.token_audience,
+        leeway: Duration::from_secs(args.token_leeway),
+        lifetime: Duration::from_secs(args.token_lifetime),
+        refresh_grace: Duration::from_secs(args.token_refresh_grace),
+        max_session: Duration::from_secs(args.max_session_length),
+        signing_keys: signing_keys.clone(),
+    };
+    // Without Redis in use already the balancer runs without it, e.g. for local development
+    let default_audit = if matches!(args.auth, Auth::Redis)
+        || args.session_store == SessionStore::Redis
+        || args.cache == Cache::RedisCache
+    {
+        Audit::Redis
+    } else {
+        Audit::Disabled
+    };
+    let mut auth_manager: Box<dyn AuthManager + Sync + Send> = match args.auth {
+        Auth::Redis => {
+            let mut auth_manager =
+                RedisAuthManager::new(args.redis_url.clone(), token_config).await;
+            if args.migrate_passwords {
+                info!("Migrating plaintext passwords");
+                auth_manager
+                    .migrate_passwords()
+                    .await
+                    .expect("Cannot migrate passwords");
+            }
+            Box::new(auth_manager)
+        }
+        Auth::Local => Box::new(LocalAuthManager::new(args.auth_file, token_config)),
+        Auth::Sqlite(path) => Box::new(
+            SqliteAuthManager::new(&path, token_config).expect("Cannot open auth database"),
+        ),
+    };
+    for username in args.admin_users {
+        match auth_manager.grant_role(username.clone(), Role::Admin).await {
+            Ok(_) => info!("Granted admin role to {}", username),
+            Err(e) => warn!("Cannot grant admin role to {}: {}", username, e),
+        }
+    }
+
+    let audit_log: Box<dyn AuditLog + Sync + Send> = match args.audit.unwrap_or(default_audit) {
+        Audit::File => {
+            Box::new(FileAuditLog::new(args.audit_file.unwrap()).expect("Cannot open audit file"))
+        }
+        Audit::Redis => Box::new(
+            RedisAuditLog::new(args.redis_url.clone())
+                .await
+                .expect("Cannot connect to Redis for the audit log"),
+        ),
+        Audit::Disabled => {
+            warn!("Audit events are not recorded, see --audit");
+            Box::new(NoAuditLog)
+        }
+    };
+
+    let oidc_provider = match args.oidc_issuer {
+        Some(issuer) => {
+            info!("Discovering OIDC provider {}", issuer);
+            let config = OidcConfig {
+                issuer,
+                client_id: args.oidc_client_id.unwrap(),
+                client_secret: args.oidc_client_secret,
+                redirect_url: args.oidc_redirect_url.unwrap(),
+                scopes: args.oidc_scopes,
+                username_claim: args.oidc_username_claim,
+            };
+            let provider = OidcProvider::discover(config)
+                .await
+                .expect("Cannot discover OIDC provider");
+            Some(Arc::new(provider))
+        }
+        None => None,
+    };
+
+    let job_template = match &args.job_template {
+        Some(source) => JobTemplate::load(source, args.namespace.as_deref())
+            .await
+            .expect("Cannot load job template"),
+        None => JobTemplate::default_template(),
+    };
+
+    let kubernetes_config = KubernetesConfig {
+        namespace: args.namespace,
+        job_name_prefix: args.job_name_prefix,
+        hash_job_names: args.hash_job_names,
+        labels: kubernetes_host::parse_key_values(&args.instance_labels)
+            .expect("Cannot parse instance labels"),
+        annotations: kubernetes_host::parse_key_values(&args.instance_annotations)
+            .expect("Cannot parse instance annotations"),
+        port: args.instance_port,
+        start_timeout: std::time::Duration::from_secs(args.instance_start_timeout),
+        create_services: args.instance_services,
+        cluster_domain: args.cluster_domain,
+    };
+
+    let readiness = ReadinessCheck {
+        path: args.readiness_path,
+        timeout: std::time::Duration::from_secs(args.readiness_timeout),
+        initial_delay: std::time::Duration::from_millis(100),
+        max_delay: std::time::Duration::from_secs(2),
+    };
+
     info!("Preparing `instance_host` and `url_cache`");
     let data = Data::new(Mutex::new(AppState {
         instance_host: match args.host {
-            Host::Kubernetes => Box::new(KubernetesHost::new()),
-            Host::Localhost => Box::new(LocalHost::new(args.app_path)),
+            Host::Kubernetes => Arc::new(KubernetesHost::new(
+                kubernetes_config,
+                job_template,
+                readiness,
+            )),
+            Host::Localhost => Arc::new(LocalHost::new(args.app_path, readiness)),
         },
-        auth_manager: Box::new(RedisAuthManager::new(args.redis_url.clone()).await),
+        auth_manager,
+        audit_log,
+        signing_keys,
+        oidc_provider,
+        session_store,
         use_cache_query: args.cache_query_url.is_some(),
+        login_throttle: LoginThrottle {
+            free_attempts: args.login_free_attempts,
+            max_delay: args.login_max_delay,
+            ..LoginThrottle::default()
+        },
+        username_policy: UsernamePolicy {
+            min_length: args.username_min_length,
+            max_length: args.username_max_length,
+            pattern: username_pattern,
+            reserved: args.reserved_usernames,
+        },
+        guest_lifetime: args.guest_lifetime,
+        max_guests: args.max_guests,
         //TODO: investigate Handle::block_on because
         //I dont like having asyncronous new method
         url_cache: match args.cache {
@@ -127,12 +616,18 @@ async fn main() -> std::io::Result<()> {
         },
     }));
 
+    actix_web::rt::spawn(cleanup_guests(data.clone()));
+
     let cache_server_data = data.clone();
     let proxy_data = data.clone();
+    let proxy_session_config = session_config.clone();
+    let trusted_proxies = Data::new(TrustedProxies::new(args.trusted_proxies));
+    let proxy_trusted_proxies = trusted_proxies.clone();
 
     let balancer = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(trusted_proxies.clone())
             .service(
                 web::scope("/instance")
                     .service(
@@ -147,10 +642,49 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/auth")
                     .route("/register", web::post().to(auth::register))
+                    .route("/guest", web::post().to(auth::create_guest))
+                    .route("/guest/upgrade", web::post().to(auth::upgrade_guest))
+                    .route("/.well-known/jwks.json", web::get().to(auth::jwks))
                     .route("/login", web::post().to(auth::login))
-                    .route("/logout", web::post().to(auth::logout)),
+                    .route("/oidc/start", web::get().to(auth::oidc_start))
+                    .route("/oidc/callback", web::get().to(auth::oidc_callback))
+                    .route("/2fa/verify", web::post().to(auth::verify_two_factor))
+                    .route("/2fa/enroll", web::post().to(auth::begin_totp_enrollment))
+                    .route(
+                        "/2fa/confirm",
+                        web::post().to(auth::confirm_totp_enrollment),
+                    )
+                    .route("/2fa/disable", web::post().to(auth::disable_totp))
+                    .route("/refresh", web::post().to(auth::refresh))
+                    .route("/logout", web::post().to(auth::logout))
+                    .route("/logout-all", web::post().to(auth::logout_all))
+                    .route("/password", web::post().to(auth::change_password))
+                    .route("/password/reset", web::post().to(auth::reset_password))
+                    .route("/account", web::delete().to(auth::delete_account))
+                    .route("/keys", web::get().to(auth::list_api_keys))
+                    .route("/keys", web::post().to(auth::create_api_key))
+                    .route("/keys/{id}", web::delete().to(auth::revoke_api_key)),
             )
-            .wrap(session_middleware())
+            .service(
+                web::scope("/admin")
+                    .route("/users", web::get().to(admin::list_users))
+                    .route("/instance/stop", web::post().to(admin::stop_instance))
+                    .route("/roles/grant", web::post().to(admin::grant_role))
+                    .route("/roles/revoke", web::post().to(admin::revoke_role))
+                    .route(
+                        "/password-reset",
+                        web::post().to(admin::create_password_reset),
+                    )
+                    .route("/sessions", web::get().to(admin::list_sessions))
+                    .route("/audit", web::get().to(admin::query_audit_log))
+                    .route(
+                        "/sessions/terminate",
+                        web::post().to(admin::terminate_sessions),
+                    ),
+            )
+            .wrap(session_config.middleware())
+            .wrap(KeyRotation::new(&session_config))
+            .wrap(CsrfProtection::new(&session_config))
     })
     .bind(("0.0.0.0", args.port))?
     .run();
@@ -168,9 +702,12 @@ async fn main() -> std::io::Result<()> {
     let proxy = HttpServer::new(move || {
         App::new()
             .app_data(proxy_data.clone())
+            .app_data(proxy_trusted_proxies.clone())
             .service(proxy_server::get_proxy)
             .service(proxy_server::post_proxy)
-            .wrap(session_middleware())
+            .wrap(proxy_session_config.middleware())
+            .wrap(KeyRotation::new(&proxy_session_config))
+            .wrap(CsrfProtection::new(&proxy_session_config))
     })
     .bind(("0.0.0.0", args.proxy_port))?
     .run();
@@ -179,3 +716,34 @@ async fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+// Deletes expired guests and stops their instances
+async fn cleanup_guests(data: Data<Mutex<AppState>>) {
+    let mut interval =
+        actix_web::rt::time::interval(std::time::Duration::from_secs(GUEST_CLEANUP_INTERVAL));
+    loop {
+        interval.tick().await;
+        let (expired, instance_host) = {
+            let mut data = data.lock().await;
+            let expired = match data.auth_manager.remove_expired_guests().await {
+                Ok(expired) => expired,
+                Err(e) => {
+                    error!("Cannot remove expired guests: {}", e);
+                    continue;
+                }
+            };
+            for username in &expired {
+                data.url_cache.remove(username.clone()).await;
+            }
+            (expired, data.instance_host.clone())
+        };
+        // Stopping takes a while, requests of other users are not held up meanwhile
+        for username in expired {
+            info!("Guest {} expired", username);
+            // The guest might never have started an instance
+            if let Err(e) = instance_host.stop_instance(username.clone()).await {
+                info!("No instance stopped for guest {}: {}", username, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::audit::{AuditAction, AuditEvent, AuditQuery};
+use crate::auth_manager::authenticated_user::AdminUser;
+use crate::auth_manager::role::Role;
+use crate::AppState;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+const SESSIONS_NOT_LISTED: &str = "Sessions are stored in cookies, use a server-side session store";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserPostRequest {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TerminateSessionsRequest {
+    pub username: String,
+    // Terminates all sessions of the user if not set
+    pub id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RolePostRequest {
+    pub username: String,
+    pub role: Role,
+}
+
+pub async fn list_users(data: web::Data<Mutex<AppState>>, _admin: AdminUser) -> HttpResponse {
+    let mut data = data.lock().await;
+    match data.auth_manager.list_users().await {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+pub async fn stop_instance(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<UserPostRequest>,
+    admin: AdminUser,
+) -> HttpResponse {
+    info!(
+        "Admin {} stops instance of {}",
+        admin.0.username, info.username
+    );
+    let mut data = data.lock().await;
+    match data
+        .instance_host
+        .stop_instance(info.username.clone())
+        .await
+    {
+        Ok(_) => {
+            // Otherwise the proxy keeps routing to the stopped instance
+            data.url_cache.remove(info.username.clone()).await;
+            let event =
+                AuditEvent::success(&request, AuditAction::AdminStopInstance, &info.username);
+            data.audit_log.log(event).await;
+            HttpResponse::Ok().body("done")
+        }
+        Err(_) => {
+            let event = AuditEvent::failure(
+                &request,
+                AuditAction::AdminStopInstance,
+                Some(&info.username),
+            );
+            data.audit_log.log(event).await;
+            HttpResponse::InternalServerError().body("Instance could not be stopped")
+        }
+    }
+}
+
+pub async fn grant_role(
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<RolePostRequest>,
+    admin: AdminUser,
+) -> HttpResponse {
+    info!(
+        "Admin {} grants {} to {}",
+        admin.0.username, info.role, info.username
+    );
+    let mut data = data.lock().await;
+    match data
+        .auth_manager
+        .grant_role(info.username.clone(), info.role)
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().body(()),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+pub async fn revoke_role(
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<RolePostRequest>,
+    admin: AdminUser,
+) -> HttpResponse {
+    info!(
+        "Admin {} revokes {} from {}",
+        admin.0.username, info.role, info.username
+    );
+    let mut data = data.lock().await;
+    match data
+        .auth_manager
+        .revoke_role(info.username.clone(), info.role)
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().body(()),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+pub async fn create_password_reset(
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<UserPostRequest>,
+    admin: AdminUser,
+) -> HttpResponse {
+    info!(
+        "Admin {} issues password reset for {}",
+        admin.0.username, info.username
+    );
+    let mut data = data.lock().await;
+    match data
+        .auth_manager
+        .create_password_reset(info.username.clone())
+        .await
+    {
+        Ok(reset_token) => HttpResponse::Ok().body(reset_token),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+pub async fn list_sessions(
+    data: web::Data<Mutex<AppState>>,
+    info: web::Query<UserPostRequest>,
+    _admin: AdminUser,
+) -> HttpResponse {
+    let data = data.lock().await;
+    let store = match &data.session_store {
+        Some(store) => store,
+        None => return HttpResponse::BadRequest().body(SESSIONS_NOT_LISTED),
+    };
+    match store.list_sessions(&info.username).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+pub async fn terminate_sessions(
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<TerminateSessionsRequest>,
+    admin: AdminUser,
+) -> HttpResponse {
+    let data = data.lock().await;
+    let store = match &data.session_store {
+        Some(store) => store,
+        None => return HttpResponse::BadRequest().body(SESSIONS_NOT_LISTED),
+    };
+    match &info.id {
+        Some(id) => {
+            info!(
+                "Admin {} terminates session {} of {}",
+                admin.0.username, id, info.username
+            );
+            match store.terminate_session(&info.username, id).await {
+                Ok(_) => HttpResponse::Ok().json(serde_json::json!({ "terminated": 1 })),
+                Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+            }
+        }
+        None => {
+            info!(
+                "Admin {} terminates all sessions of {}",
+                admin.0.username, info.username
+            );
+            match store.terminate_sessions(&info.username).await {
+                Ok(terminated) => {
+                    HttpResponse::Ok().json(serde_json::json!({ "terminated": terminated }))
+                }
+                Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+            }
+        }
+    }
+}
+
+pub async fn query_audit_log(
+    data: web::Data<Mutex<AppState>>,
+    query: web::Query<AuditQuery>,
+    _admin: AdminUser,
+) -> HttpResponse {
+    let mut data = data.lock().await;
+    match data.audit_log.query(&query).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -1,9 +1,17 @@
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth_manager::api_key::ApiKeyScope;
+use crate::auth_manager::authenticated_user::{self, AuthenticatedUser};
+use crate::auth_manager::oidc::{self, AuthorizationRequest};
+use crate::auth_manager::{login_throttle, token, LoginOutcome, INVALID_CREDENTIALS};
+use crate::client_ip::client_ip;
 use crate::AppState;
 
 use actix_session::Session;
-use actix_web::{web, HttpResponse};
+use actix_web::http::header::{CACHE_CONTROL, LOCATION, RETRY_AFTER};
+use actix_web::{web, HttpRequest, HttpResponse};
 use futures::lock::Mutex;
 use serde::{Deserialize, Serialize};
+use tracing::{error, info};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LoginPostRequest {
@@ -17,7 +25,61 @@ pub struct RegisterPostRequest {
     pub password: String, // hashed
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKeyPostRequest {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TwoFactorPostRequest {
+    pub username: Option<String>,
+    pub two_factor_token: Option<String>,
+    pub code: String, // TOTP or recovery code
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TotpCodePostRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DisableTotpPostRequest {
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpgradeGuestPostRequest {
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChangePasswordPostRequest {
+    pub current_password: Option<String>,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResetPasswordPostRequest {
+    pub username: String,
+    pub reset_token: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeleteAccountRequest {
+    pub password: Option<String>,
+}
+
 pub async fn register(
+    request: HttpRequest,
     data: web::Data<Mutex<AppState>>,
     info: web::Json<RegisterPostRequest>,
     session: Session,
@@ -27,13 +89,25 @@ pub async fn register(
     }
 
     let mut data = data.lock().await;
+    if let Err(violations) = data.username_policy.validate(&info.username) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "invalid_username",
+            "violations": violations,
+        }));
+    }
     let ret = data
         .auth_manager
         .register(info.username.clone(), info.password.clone())
         .await;
     match ret {
-        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        Err(e) => {
+            let event = AuditEvent::failure(&request, AuditAction::Register, Some(&info.username));
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
         Ok(token) => {
+            let event = AuditEvent::success(&request, AuditAction::Register, &info.username);
+            data.audit_log.log(event).await;
             session
                 .insert("session_token", &token)
                 .expect("Cannot set session cookie");
@@ -45,7 +119,191 @@ pub async fn register(
     }
 }
 
+fn throttle_keys(request: &HttpRequest, username: &str) -> [String; 2] {
+    let ip = client_ip(request).unwrap_or_else(|| "unknown".to_string());
+    [
+        login_throttle::user_key(username),
+        login_throttle::ip_key(&ip),
+    ]
+}
+
+async fn check_throttle(data: &mut AppState, keys: &[String]) -> Option<HttpResponse> {
+    let now = token::now_secs();
+    for key in keys {
+        let attempts = match data.auth_manager.get_login_attempts(key.clone()).await {
+            Ok(attempts) => attempts,
+            Err(e) => {
+                error!("Cannot get login attempts for {}: {}", key, e);
+                continue;
+            }
+        };
+        if let Some(secs) = data.login_throttle.retry_after(&attempts, now) {
+            return Some(
+                HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, secs.to_string()))
+                    .body("Too many failed login attempts, try again later"),
+            );
+        }
+    }
+    None
+}
+
+// A wrong password counts as a failed login wherever it is checked, otherwise
+// a stolen session could be used to guess the password without throttling
+async fn record_wrong_password(
+    data: &mut AppState,
+    keys: &[String],
+    error: &dyn std::error::Error,
+) {
+    if error.to_string() == INVALID_CREDENTIALS {
+        record_failure(data, keys).await;
+    }
+}
+
+async fn record_failure(data: &mut AppState, keys: &[String]) {
+    let expire_after = data.login_throttle.reset_after;
+    for key in keys {
+        if let Err(e) = data
+            .auth_manager
+            .record_failed_login(key.clone(), expire_after)
+            .await
+        {
+            error!("Cannot record failed login for {}: {}", key, e);
+        }
+    }
+}
+
+// OIDC accounts have no password anyone knows, logging in through the
+// identity provider within this many seconds proves the identity instead
+const OIDC_REAUTHENTICATION_WINDOW: u64 = 300;
+
+// The password to confirm an account change with, `None` if the session
+// was established by a recent OIDC login of the user
+fn password_or_oidc_login(
+    session: &Session,
+    username: &str,
+    password: &Option<String>,
+) -> Result<Option<String>, HttpResponse> {
+    if let Some(password) = password {
+        return Ok(Some(password.clone()));
+    }
+    let same_user =
+        matches!(session.get::<String>("session_username"), Ok(Some(name)) if name == username);
+    let recent = matches!(
+        session.get::<u64>("session_oidc_login"),
+        Ok(Some(at)) if at + OIDC_REAUTHENTICATION_WINDOW >= token::now_secs()
+    );
+    if same_user && recent {
+        return Ok(None);
+    }
+    Err(HttpResponse::BadRequest()
+        .body("Password required, or log in through the identity provider again"))
+}
+
+async fn start_session(
+    data: &mut AppState,
+    event: AuditEvent,
+    keys: [String; 2],
+    session: &Session,
+    username: &str,
+    token: String,
+) -> HttpResponse {
+    data.audit_log.log(event).await;
+    let [user_key, _] = keys;
+    if let Err(e) = data.auth_manager.clear_failed_logins(user_key).await {
+        error!("Cannot clear failed logins: {}", e);
+    }
+    session.remove("two_factor_token");
+    session.remove("two_factor_username");
+    session.remove("session_oidc_login");
+    session
+        .insert("session_token", &token)
+        .expect("Cannot set session cookie");
+    session
+        .insert("session_username", username)
+        .expect("Cannot set session username");
+    HttpResponse::Ok().body(token)
+}
+
+pub async fn create_guest(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    session: Session,
+) -> HttpResponse {
+    if let Ok(Some(_)) = session.get::<String>("session_token") {
+        return HttpResponse::BadRequest().body("Already logged in");
+    }
+
+    let mut data = data.lock().await;
+    match data.auth_manager.count_guests().await {
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        Ok(count) if count >= data.max_guests => {
+            return HttpResponse::ServiceUnavailable().body("No guest sessions available")
+        }
+        Ok(_) => (),
+    }
+    let lifetime = data.guest_lifetime;
+    match data.auth_manager.create_guest(lifetime).await {
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Ok((username, token)) => {
+            info!("Created guest {}", username);
+            let event = AuditEvent::success(&request, AuditAction::CreateGuest, &username);
+            data.audit_log.log(event).await;
+            session
+                .insert("session_token", &token)
+                .expect("Cannot set session cookie");
+            session
+                .insert("session_username", &username)
+                .expect("Cannot set session username");
+            HttpResponse::Ok().json(serde_json::json!({
+                "username": username,
+                "token": token,
+                "expires_in": lifetime,
+            }))
+        }
+    }
+}
+
+// The guest keeps its username and with it its instance
+pub async fn upgrade_guest(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<UpgradeGuestPostRequest>,
+    user: AuthenticatedUser,
+    session: Session,
+) -> HttpResponse {
+    if let Err(e) = user.require_session() {
+        return HttpResponse::from_error(e);
+    }
+
+    let mut data = data.lock().await;
+    let ret = data
+        .auth_manager
+        .upgrade_guest(user.username.clone(), info.password.clone())
+        .await;
+    match ret {
+        Err(e) => {
+            let event =
+                AuditEvent::failure(&request, AuditAction::UpgradeGuest, Some(&user.username));
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Ok(token) => {
+            info!("Upgraded guest {}", user.username);
+            let event = AuditEvent::success(&request, AuditAction::UpgradeGuest, &user.username);
+            data.audit_log.log(event).await;
+            if let Ok(Some(_)) = session.get::<String>("session_token") {
+                session
+                    .insert("session_token", &token)
+                    .expect("Cannot set session cookie");
+            }
+            HttpResponse::Ok().body(token)
+        }
+    }
+}
+
 pub async fn login(
+    request: HttpRequest,
     data: web::Data<Mutex<AppState>>,
     info: web::Json<LoginPostRequest>,
     session: Session,
@@ -54,35 +312,580 @@ pub async fn login(
         return HttpResponse::BadRequest().body("Already logged in");
     }
 
+    let keys = throttle_keys(&request, &info.username);
     let mut data = data.lock().await;
+    if let Some(response) = check_throttle(&mut data, &keys).await {
+        return response;
+    }
+
     let ret = data
         .auth_manager
         .login(info.username.clone(), info.password.clone())
         .await;
     match ret {
-        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        Err(e) => {
+            record_failure(&mut data, &keys).await;
+            let event = AuditEvent::failure(&request, AuditAction::Login, Some(&info.username));
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Ok(LoginOutcome::Token(token)) => {
+            let event = AuditEvent::success(&request, AuditAction::Login, &info.username);
+            start_session(&mut data, event, keys, &session, &info.username, token).await
+        }
+        // Failed logins are only cleared once the second factor is verified
+        Ok(LoginOutcome::TwoFactorRequired(two_factor_token)) => {
+            session
+                .insert("two_factor_token", &two_factor_token)
+                .expect("Cannot set session cookie");
+            session
+                .insert("two_factor_username", &info.username)
+                .expect("Cannot set session username");
+            HttpResponse::Accepted().json(serde_json::json!({
+                "status": "2fa_required",
+                "two_factor_token": two_factor_token,
+            }))
+        }
+    }
+}
+
+// Completes a login answered with "2fa_required". Clients without cookies
+// pass the username and token from that answer, others the code only.
+pub async fn verify_two_factor(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<TwoFactorPostRequest>,
+    session: Session,
+) -> HttpResponse {
+    if let Ok(Some(_)) = session.get::<String>("session_token") {
+        return HttpResponse::BadRequest().body("Already logged in");
+    }
+
+    let username = match &info.username {
+        Some(username) => Some(username.clone()),
+        None => session.get::<String>("two_factor_username").unwrap_or(None),
+    };
+    let two_factor_token = match &info.two_factor_token {
+        Some(token) => Some(token.clone()),
+        None => session.get::<String>("two_factor_token").unwrap_or(None),
+    };
+    let (username, two_factor_token) = match (username, two_factor_token) {
+        (Some(username), Some(token)) => (username, token),
+        _ => return HttpResponse::BadRequest().body("No pending 2FA login"),
+    };
+
+    let keys = throttle_keys(&request, &username);
+    let mut data = data.lock().await;
+    if let Some(response) = check_throttle(&mut data, &keys).await {
+        return response;
+    }
+
+    let ret = data
+        .auth_manager
+        .verify_second_factor(username.clone(), two_factor_token, info.code.clone())
+        .await;
+    match ret {
+        Err(e) => {
+            record_failure(&mut data, &keys).await;
+            let event = AuditEvent::failure(&request, AuditAction::TwoFactorLogin, Some(&username));
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Ok(token) => {
+            let event = AuditEvent::success(&request, AuditAction::TwoFactorLogin, &username);
+            start_session(&mut data, event, keys, &session, &username, token).await
+        }
+    }
+}
+
+pub async fn oidc_start(data: web::Data<Mutex<AppState>>, session: Session) -> HttpResponse {
+    if let Ok(Some(_)) = session.get::<String>("session_token") {
+        return HttpResponse::BadRequest().body("Already logged in");
+    }
+
+    let data = data.lock().await;
+    let provider = match &data.oidc_provider {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().body("OIDC login is not configured"),
+    };
+    match provider.authorization_url() {
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Ok((url, request)) => {
+            session
+                .insert("oidc_request", &request)
+                .expect("Cannot set session cookie");
+            HttpResponse::Found()
+                .insert_header((LOCATION, url))
+                .finish()
+        }
+    }
+}
+
+// The provider redirects the browser here. Users logging in for the first
+// time get a new account, named after the configured claim of the ID token.
+pub async fn oidc_callback(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    query: web::Query<OidcCallbackQuery>,
+    session: Session,
+) -> HttpResponse {
+    let authorization = match session.remove_as::<AuthorizationRequest>("oidc_request") {
+        Some(Ok(authorization)) => authorization,
+        _ => return HttpResponse::BadRequest().body("No pending OIDC login"),
+    };
+    if let Some(error) = &query.error {
+        return HttpResponse::BadRequest().body("Identity provider error: ".to_owned() + error);
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return HttpResponse::BadRequest().body("Missing code or state"),
+    };
+    if !oidc::check_state(&authorization, state) {
+        return HttpResponse::BadRequest().body("Invalid state");
+    }
+
+    // Not holding the lock while talking to the provider
+    let provider = match &data.lock().await.oidc_provider {
+        Some(provider) => provider.clone(),
+        None => return HttpResponse::NotFound().body("OIDC login is not configured"),
+    };
+    let identity = provider.exchange_code(code, &authorization).await;
+    let mut data = data.lock().await;
+    let identity = match identity {
+        Ok(identity) => identity,
+        Err(e) => {
+            info!("OIDC login failed: {}", e);
+            let event = AuditEvent::failure(&request, AuditAction::OidcLogin, None);
+            data.audit_log.log(event).await;
+            return HttpResponse::BadRequest().body("OIDC login failed");
+        }
+    };
+
+    let linked = data
+        .auth_manager
+        .find_linked_user(identity.subject.clone())
+        .await;
+    let username = match (linked, identity.username) {
+        (Err(e), _) => return HttpResponse::InternalServerError().body(e.to_string()),
+        (Ok(Some(username)), _) => username,
+        (Ok(None), None) => {
+            return HttpResponse::BadRequest().body("Identity provider sent no username")
+        }
+        (Ok(None), Some(username)) => {
+            if let Err(violations) = data.username_policy.validate(&username) {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "invalid_username",
+                    "violations": violations,
+                }));
+            }
+            username
+        }
+    };
+
+    let ret = data
+        .auth_manager
+        .external_login(identity.subject, username.clone())
+        .await;
+    match ret {
+        Err(e) => {
+            let event = AuditEvent::failure(&request, AuditAction::OidcLogin, Some(&username));
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
         Ok(token) => {
+            let event = AuditEvent::success(&request, AuditAction::OidcLogin, &username);
+            data.audit_log.log(event).await;
             session
                 .insert("session_token", &token)
                 .expect("Cannot set session cookie");
             session
-                .insert("session_username", &info.username)
+                .insert("session_username", &username)
                 .expect("Cannot set session username");
+            session
+                .insert("session_oidc_login", token::now_secs())
+                .expect("Cannot set session cookie");
+            HttpResponse::Ok().body(token)
+        }
+    }
+}
+
+pub async fn begin_totp_enrollment(
+    data: web::Data<Mutex<AppState>>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(e) = user.require_session().and(user.require_account()) {
+        return HttpResponse::from_error(e);
+    }
+
+    let mut data = data.lock().await;
+    match data.auth_manager.begin_totp_enrollment(user.username).await {
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(enrollment) => HttpResponse::Ok().json(enrollment),
+    }
+}
+
+pub async fn confirm_totp_enrollment(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<TotpCodePostRequest>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(e) = user.require_session() {
+        return HttpResponse::from_error(e);
+    }
+
+    let mut data = data.lock().await;
+    let ret = data
+        .auth_manager
+        .confirm_totp_enrollment(user.username.clone(), info.code.clone())
+        .await;
+    match ret {
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(recovery_codes) => {
+            info!("Enabled 2FA for {}", user.username);
+            let event = AuditEvent::success(&request, AuditAction::EnableTwoFactor, &user.username);
+            data.audit_log.log(event).await;
+            HttpResponse::Ok().json(serde_json::json!({ "recovery_codes": recovery_codes }))
+        }
+    }
+}
+
+pub async fn disable_totp(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<DisableTotpPostRequest>,
+    user: AuthenticatedUser,
+    session: Session,
+) -> HttpResponse {
+    if let Err(e) = user.require_session() {
+        return HttpResponse::from_error(e);
+    }
+
+    let password = match password_or_oidc_login(&session, &user.username, &info.password) {
+        Ok(password) => password,
+        Err(response) => return response,
+    };
+    let keys = throttle_keys(&request, &user.username);
+    let mut data = data.lock().await;
+    if let Some(response) = check_throttle(&mut data, &keys).await {
+        return response;
+    }
+    let ret = data
+        .auth_manager
+        .disable_totp(user.username.clone(), password)
+        .await;
+    match ret {
+        Err(e) => {
+            record_wrong_password(&mut data, &keys, e.as_ref()).await;
+            let event = AuditEvent::failure(
+                &request,
+                AuditAction::DisableTwoFactor,
+                Some(&user.username),
+            );
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Ok(_) => {
+            info!("Disabled 2FA for {}", user.username);
+            let event =
+                AuditEvent::success(&request, AuditAction::DisableTwoFactor, &user.username);
+            data.audit_log.log(event).await;
+            HttpResponse::Ok().body(())
+        }
+    }
+}
+
+// Without signing keys there is nothing to publish, the per-user keys are secret
+pub async fn jwks(data: web::Data<Mutex<AppState>>) -> HttpResponse {
+    let data = data.lock().await;
+    let jwks = match &data.signing_keys {
+        Some(signing_keys) => signing_keys.jwks(),
+        None => serde_json::json!({ "keys": [] }),
+    };
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "public, max-age=300"))
+        .json(jwks)
+}
+
+pub async fn refresh(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    session: Session,
+) -> HttpResponse {
+    let (username, token) = match authenticated_user::credentials(&request) {
+        Ok(credentials) => credentials,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let mut data = data.lock().await;
+    match data.auth_manager.refresh_token(username, token).await {
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(token) => {
+            if let Ok(Some(_)) = session.get::<String>("session_token") {
+                session
+                    .insert("session_token", &token)
+                    .expect("Cannot set session cookie");
+            }
             HttpResponse::Ok().body(token)
         }
     }
 }
 
-pub async fn logout(_data: web::Data<Mutex<AppState>>, session: Session) -> HttpResponse {
-    if let Err(_) = session.get::<String>("session_token") {
-        return HttpResponse::BadRequest().body("Not logged in");
+pub async fn logout(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    session: Session,
+) -> HttpResponse {
+    // Bearer token or session cookie
+    let (username, token) = match authenticated_user::credentials(&request) {
+        Ok(credentials) => credentials,
+        Err(_) => return HttpResponse::BadRequest().body("Not logged in"),
+    };
+
+    let mut data = data.lock().await;
+    match data
+        .auth_manager
+        .revoke_token(username.clone(), token)
+        .await
+    {
+        Ok(_) => {
+            let event = AuditEvent::success(&request, AuditAction::Logout, &username);
+            data.audit_log.log(event).await;
+        }
+        // Token is already expired or invalid, there is nothing to revoke
+        Err(e) => info!("Token not revoked: {}", e),
     }
 
-    match session.remove("session_token") {
-        Some(_) => {
-            session.remove("session_username");
+    session.remove("session_token");
+    session.remove("session_username");
+    HttpResponse::Ok().body(())
+}
+
+pub async fn logout_all(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    user: AuthenticatedUser,
+    session: Session,
+) -> HttpResponse {
+    if let Err(e) = user.require_session() {
+        return HttpResponse::from_error(e);
+    }
+
+    let mut data = data.lock().await;
+    if let Err(e) = data
+        .auth_manager
+        .revoke_all_tokens(user.username.clone())
+        .await
+    {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    let event = AuditEvent::success(&request, AuditAction::LogoutAll, &user.username);
+    data.audit_log.log(event).await;
+
+    session.remove("session_token");
+    session.remove("session_username");
+    HttpResponse::Ok().body(())
+}
+
+pub async fn change_password(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<ChangePasswordPostRequest>,
+    user: AuthenticatedUser,
+    session: Session,
+) -> HttpResponse {
+    if let Err(e) = user.require_session().and(user.require_account()) {
+        return HttpResponse::from_error(e);
+    }
+
+    let current_password =
+        match password_or_oidc_login(&session, &user.username, &info.current_password) {
+            Ok(password) => password,
+            Err(response) => return response,
+        };
+    let keys = throttle_keys(&request, &user.username);
+    let mut data = data.lock().await;
+    if let Some(response) = check_throttle(&mut data, &keys).await {
+        return response;
+    }
+    let ret = data
+        .auth_manager
+        .change_password(
+            user.username.clone(),
+            current_password,
+            info.new_password.clone(),
+        )
+        .await;
+    match ret {
+        Err(e) => {
+            record_wrong_password(&mut data, &keys, e.as_ref()).await;
+            let event =
+                AuditEvent::failure(&request, AuditAction::ChangePassword, Some(&user.username));
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Ok(token) => {
+            let event = AuditEvent::success(&request, AuditAction::ChangePassword, &user.username);
+            data.audit_log.log(event).await;
+            if let Ok(Some(_)) = session.get::<String>("session_token") {
+                session
+                    .insert("session_token", &token)
+                    .expect("Cannot set session cookie");
+            }
+            HttpResponse::Ok().body(token)
+        }
+    }
+}
+
+pub async fn reset_password(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<ResetPasswordPostRequest>,
+) -> HttpResponse {
+    let mut data = data.lock().await;
+    let ret = data
+        .auth_manager
+        .reset_password(
+            info.username.clone(),
+            info.reset_token.clone(),
+            info.new_password.clone(),
+        )
+        .await;
+    match ret {
+        Err(e) => {
+            let event =
+                AuditEvent::failure(&request, AuditAction::ResetPassword, Some(&info.username));
+            data.audit_log.log(event).await;
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Ok(_) => {
+            let event = AuditEvent::success(&request, AuditAction::ResetPassword, &info.username);
+            data.audit_log.log(event).await;
+            let key = login_throttle::user_key(&info.username);
+            if let Err(e) = data.auth_manager.clear_failed_logins(key).await {
+                error!("Cannot clear failed logins: {}", e);
+            }
+            HttpResponse::Ok().body(())
+        }
+    }
+}
+
+pub async fn delete_account(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<DeleteAccountRequest>,
+    user: AuthenticatedUser,
+    session: Session,
+) -> HttpResponse {
+    if let Err(e) = user.require_session() {
+        return HttpResponse::from_error(e);
+    }
+
+    let password = match password_or_oidc_login(&session, &user.username, &info.password) {
+        Ok(password) => password,
+        Err(response) => return response,
+    };
+    let keys = throttle_keys(&request, &user.username);
+    let mut data = data.lock().await;
+    if let Some(response) = check_throttle(&mut data, &keys).await {
+        return response;
+    }
+    let username = user.username;
+    if let Err(e) = data
+        .auth_manager
+        .delete_user(username.clone(), password)
+        .await
+    {
+        record_wrong_password(&mut data, &keys, e.as_ref()).await;
+        let event = AuditEvent::failure(&request, AuditAction::DeleteAccount, Some(&username));
+        data.audit_log.log(event).await;
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let event = AuditEvent::success(&request, AuditAction::DeleteAccount, &username);
+    data.audit_log.log(event).await;
+
+    // The user might not have a running instance
+    if let Err(e) = data.instance_host.stop_instance(username.clone()).await {
+        info!("No instance stopped for deleted user {}: {}", username, e);
+    }
+    data.url_cache.remove(username).await;
+
+    session.remove("session_token");
+    session.remove("session_username");
+    HttpResponse::Ok().body(())
+}
+
+pub async fn list_api_keys(
+    data: web::Data<Mutex<AppState>>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(e) = user.require_session() {
+        return HttpResponse::from_error(e);
+    }
+
+    let mut data = data.lock().await;
+    match data.auth_manager.list_api_keys(user.username).await {
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Ok(keys) => HttpResponse::Ok().json(keys),
+    }
+}
+
+pub async fn create_api_key(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    info: web::Json<ApiKeyPostRequest>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(e) = user.require_session().and(user.require_account()) {
+        return HttpResponse::from_error(e);
+    }
+
+    let mut data = data.lock().await;
+    let ret = data
+        .auth_manager
+        .create_api_key(
+            user.username.clone(),
+            info.name.clone(),
+            info.scopes.clone(),
+        )
+        .await;
+    match ret {
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok((key_info, key)) => {
+            info!("Created API key {} for {}", key_info.id, user.username);
+            let event = AuditEvent::success(&request, AuditAction::CreateApiKey, &user.username);
+            data.audit_log.log(event).await;
+            HttpResponse::Created().json(serde_json::json!({
+                "key": key,
+                "info": key_info,
+            }))
+        }
+    }
+}
+
+pub async fn revoke_api_key(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    path: web::Path<String>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(e) = user.require_session() {
+        return HttpResponse::from_error(e);
+    }
+
+    let mut data = data.lock().await;
+    let id = path.into_inner();
+    match data
+        .auth_manager
+        .revoke_api_key(user.username.clone(), id.clone())
+        .await
+    {
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(_) => {
+            info!("Revoked API key {} of {}", id, user.username);
+            let event = AuditEvent::success(&request, AuditAction::RevokeApiKey, &user.username);
+            data.audit_log.log(event).await;
             HttpResponse::Ok().body(())
         }
-        None => HttpResponse::BadRequest().body("Not logged in"),
     }
 }
@@ -1,22 +1,33 @@
-use crate::{auth_manager, AppState};
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth_manager::api_key::ApiKeyScope;
+use crate::auth_manager::authenticated_user::AuthenticatedUser;
+use crate::instance_host::InstanceStartError;
+use crate::AppState;
 
-use actix_session::Session;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use futures::lock::Mutex;
 
-pub async fn start_instance(data: web::Data<Mutex<AppState>>, session: Session) -> HttpResponse {
-    let mut data = data.lock().await;
-    if let Err(e) = auth_manager::authorize_from_session(&session, &mut data.auth_manager).await {
-        return HttpResponse::BadRequest().body(e.to_string());
-    };
+pub async fn start_instance(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(e) = user.require_scope(ApiKeyScope::InstanceStart) {
+        return HttpResponse::from_error(e);
+    }
 
-    let username = session.get::<String>("session_username").unwrap().unwrap();
+    let username = user.username;
 
     // TODO: check if already in cache
     // TODO: if existing user, first stop previous instance
-    let new_instance = data.instance_host.start_instance(username.clone()).await;
+    // Not holding the lock while the instance starts, other requests would wait for it
+    let instance_host = data.lock().await.instance_host.clone();
+    let new_instance = instance_host.start_instance(username.clone()).await;
+    let mut data = data.lock().await;
     match new_instance {
         Ok(instance) => {
+            let event = AuditEvent::success(&request, AuditAction::StartInstance, &username);
+            data.audit_log.log(event).await;
             data.url_cache
                 .set(username, instance.get_url_with_port())
                 .await;
@@ -24,24 +35,43 @@ pub async fn start_instance(data: web::Data<Mutex<AppState>>, session: Session)
         }
         Err(e) => {
             eprintln!("Error: {e}");
-            HttpResponse::InternalServerError().body("Oh no error baby")
+            let event = AuditEvent::failure(&request, AuditAction::StartInstance, Some(&username));
+            data.audit_log.log(event).await;
+            match e.downcast_ref::<InstanceStartError>() {
+                Some(InstanceStartError::Timeout(_) | InstanceStartError::NotReady(_)) => {
+                    HttpResponse::GatewayTimeout().body("Instance did not start in time")
+                }
+                _ => HttpResponse::InternalServerError().body("Oh no error baby"),
+            }
         }
     }
 }
 
-pub async fn stop_instance(data: web::Data<Mutex<AppState>>, session: Session) -> HttpResponse {
-    let mut data = data.lock().await;
-    if let Err(e) = auth_manager::authorize_from_session(&session, &mut data.auth_manager).await {
-        return HttpResponse::BadRequest().body(e.to_string());
-    };
+pub async fn stop_instance(
+    request: HttpRequest,
+    data: web::Data<Mutex<AppState>>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(e) = user.require_scope(ApiKeyScope::InstanceStop) {
+        return HttpResponse::from_error(e);
+    }
 
-    let username = session.get::<String>("session_username").unwrap().unwrap();
+    let username = user.username;
 
     // TODO: remove from cache
     // TODO: save state of scene host?
-    match data.instance_host.stop_instance(username).await {
+    let instance_host = data.lock().await.instance_host.clone();
+    let stopped = instance_host.stop_instance(username.clone()).await;
+    let mut data = data.lock().await;
+    match stopped {
         Ok(_) => (),
-        Err(_) => return HttpResponse::InternalServerError().body("Instance could not be stopped"),
+        Err(_) => {
+            let event = AuditEvent::failure(&request, AuditAction::StopInstance, Some(&username));
+            data.audit_log.log(event).await;
+            return HttpResponse::InternalServerError().body("Instance could not be stopped");
+        }
     }
+    let event = AuditEvent::success(&request, AuditAction::StopInstance, &username);
+    data.audit_log.log(event).await;
     HttpResponse::Ok().body("done")
 }
@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod auth;
 pub mod cache_server;
 pub mod instance_server;
@@ -1,7 +1,8 @@
-use crate::{auth_manager, AppState};
+use crate::auth_manager::api_key::ApiKeyScope;
+use crate::auth_manager::authenticated_user::AuthenticatedUser;
+use crate::AppState;
 
 use actix_proxy::IntoHttpResponse;
-use actix_session::Session;
 use actix_web::web::Bytes;
 use actix_web::{get, post, web, HttpResponse};
 use awc;
@@ -13,22 +14,20 @@ pub async fn get_proxy(
     data: web::Data<Mutex<AppState>>,
     path: web::Path<String>,
     bytes: Bytes,
-    session: Session,
+    user: AuthenticatedUser,
 ) -> HttpResponse {
-    let mut data = data.lock().await;
-
-    if let Err(e) = auth_manager::authorize_from_session(&session, &mut data.auth_manager).await {
-        return HttpResponse::BadRequest().body(e.to_string());
-    };
+    if let Err(e) = user.require_scope(ApiKeyScope::Proxy) {
+        return HttpResponse::from_error(e);
+    }
 
-    let username = session.get::<String>("session_username").unwrap().unwrap();
+    let mut data = data.lock().await;
+    let username = user.username;
 
-    let url;
-    if data.use_cache_query {
-        url = data.url_cache.get_or_query(username).await;
+    let url = if data.use_cache_query {
+        data.url_cache.get_or_query(username).await
     } else {
-        url = data.url_cache.get(username).await;
-    }
+        data.url_cache.get(username).await
+    };
 
     if let Some(url) = url {
         let client = awc::Client::default();
@@ -52,22 +51,20 @@ pub async fn post_proxy(
     data: web::Data<Mutex<AppState>>,
     path: web::Path<String>,
     bytes: Bytes,
-    session: Session,
+    user: AuthenticatedUser,
 ) -> HttpResponse {
-    let mut data = data.lock().await;
-
-    if let Err(e) = auth_manager::authorize_from_session(&session, &mut data.auth_manager).await {
-        return HttpResponse::BadRequest().body(e.to_string());
-    };
+    if let Err(e) = user.require_scope(ApiKeyScope::Proxy) {
+        return HttpResponse::from_error(e);
+    }
 
-    let username = session.get::<String>("session_username").unwrap().unwrap();
+    let mut data = data.lock().await;
+    let username = user.username;
 
-    let url;
-    if data.use_cache_query {
-        url = data.url_cache.get_or_query(username).await;
+    let url = if data.use_cache_query {
+        data.url_cache.get_or_query(username).await
     } else {
-        url = data.url_cache.get(username).await;
-    }
+        data.url_cache.get(username).await
+    };
 
     if let Some(url) = url {
         let client = awc::Client::default();
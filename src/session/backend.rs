@@ -0,0 +1,166 @@
+use crate::auth_manager::token;
+use crate::session::ServerSessionStore;
+
+use actix_session::storage::{
+    CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore, UpdateError,
+};
+use actix_web::cookie::time::Duration;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Where the session middleware keeps session state. `Cookie` stores
+// all of it in the (encrypted) cookie, `Server` only the session key.
+#[derive(Clone)]
+pub enum SessionBackend {
+    Cookie,
+    Server(Arc<dyn ServerSessionStore>),
+}
+
+fn generate_session_key() -> SessionKey {
+    // 256 bit
+    SessionKey::try_from(token::generate_id() + &token::generate_id()).unwrap()
+}
+
+fn ttl_secs(ttl: &Duration) -> u64 {
+    ttl.whole_seconds().max(1) as u64
+}
+
+fn other(e: Box<dyn std::error::Error>) -> anyhow::Error {
+    anyhow::anyhow!(e.to_string())
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SessionBackend {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().load(session_key).await,
+            SessionBackend::Server(store) => store
+                .load(session_key.as_ref())
+                .await
+                .map_err(|e| LoadError::Other(other(e))),
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().save(session_state, ttl).await,
+            SessionBackend::Server(store) => {
+                let session_key = generate_session_key();
+                store
+                    .create(session_key.as_ref(), session_state, ttl_secs(ttl))
+                    .await
+                    .map_err(|e| SaveError::Other(other(e)))?;
+                Ok(session_key)
+            }
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            SessionBackend::Cookie => {
+                CookieSessionStore::default()
+                    .update(session_key, session_state, ttl)
+                    .await
+            }
+            // A terminated session is not brought back, the cookie then
+            // points to nothing and the next request starts a new session
+            SessionBackend::Server(store) => {
+                store
+                    .update(session_key.as_ref(), session_state, ttl_secs(ttl))
+                    .await
+                    .map_err(|e| UpdateError::Other(other(e)))?;
+                Ok(session_key)
+            }
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Cookie => Ok(()),
+            SessionBackend::Server(store) => store
+                .update_ttl(session_key.as_ref(), ttl_secs(ttl))
+                .await
+                .map_err(other),
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Cookie => Ok(()),
+            SessionBackend::Server(store) => {
+                store.delete(session_key.as_ref()).await.map_err(other)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::local_session_store::LocalSessionStore;
+    use actix_session::{Session, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn login(session: Session) -> HttpResponse {
+        session.insert("session_username", "alice").unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn whoami(session: Session) -> HttpResponse {
+        match session.get::<String>("session_username").unwrap() {
+            Some(username) => HttpResponse::Ok().body(username),
+            None => HttpResponse::Unauthorized().finish(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_terminated_session() {
+        let store = Arc::new(LocalSessionStore::new());
+        let backend = SessionBackend::Server(store.clone());
+        let app = test::init_service(
+            App::new()
+                .route("/login", web::post().to(login))
+                .route("/whoami", web::get().to(whoami))
+                .wrap(SessionMiddleware::new(backend, Key::generate())),
+        )
+        .await;
+
+        let res =
+            test::call_service(&app, test::TestRequest::post().uri("/login").to_request()).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let whoami = || {
+            test::TestRequest::get()
+                .uri("/whoami")
+                .cookie(cookie.clone())
+                .to_request()
+        };
+        let res = test::call_service(&app, whoami()).await;
+        assert_eq!(test::read_body(res).await, "alice");
+
+        let sessions = store.list_sessions("alice").await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        store
+            .terminate_session("alice", &sessions[0].id)
+            .await
+            .unwrap();
+        let res = test::call_service(&app, whoami()).await;
+        assert_eq!(res.status(), 401);
+    }
+}
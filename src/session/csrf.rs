@@ -0,0 +1,242 @@
+use crate::session::SessionConfig;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::rc::Rc;
+use url::Url;
+
+// Rejects state-changing requests authenticated by the session cookie unless
+// their Origin, or Referer if the browser sent no Origin, is the host the
+// request was sent to or one of the allowed origins. Requests without the
+// session cookie, e.g. with a bearer token or API key, cannot be forged
+// by another site and are let through.
+
+pub struct CsrfProtection {
+    cookie_name: Rc<String>,
+    allowed_origins: Rc<Vec<String>>,
+}
+
+impl CsrfProtection {
+    pub fn new(config: &SessionConfig) -> CsrfProtection {
+        CsrfProtection {
+            cookie_name: Rc::new(config.cookie_name.clone()),
+            allowed_origins: Rc::new(config.allowed_origins.clone()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service,
+            cookie_name: self.cookie_name.clone(),
+            allowed_origins: self.allowed_origins.clone(),
+        }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: S,
+    cookie_name: Rc<String>,
+    allowed_origins: Rc<Vec<String>>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if is_safe(req.method()) || req.cookie(&self.cookie_name).is_none() {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        }
+
+        let source = req
+            .headers()
+            .get(header::ORIGIN)
+            .or_else(|| req.headers().get(header::REFERER))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let host = req.connection_info().host().to_owned();
+        let allowed = match source {
+            Some(source) => is_allowed(&source, &host, &self.allowed_origins),
+            None => false,
+        };
+        if allowed {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        }
+
+        Box::pin(async move {
+            let res = HttpResponse::Forbidden().body("Cross-site request rejected");
+            Ok(req.into_response(res).map_into_right_body())
+        })
+    }
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// `source` is an Origin or Referer header value
+fn is_allowed(source: &str, host: &str, allowed_origins: &[String]) -> bool {
+    let url = match Url::parse(source) {
+        Ok(url) => url,
+        // Includes the opaque `null` origin
+        Err(_) => return false,
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let origin = url.origin().ascii_serialization();
+    if allowed_origins.contains(&origin) {
+        return true;
+    }
+
+    // The Host header has no scheme, the default port depends on the one of the origin
+    match Url::parse(&format!("{}://{}", url.scheme(), host)) {
+        Ok(host) => {
+            host.host_str() == url.host_str()
+                && host.port_or_known_default() == url.port_or_known_default()
+        }
+        Err(_) => false,
+    }
+}
+
+// Normalizes an origin from the command line, e.g. `https://Example.com:443/` -> `https://example.com`
+pub fn parse_origin(origin: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let url = Url::parse(origin)?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(format!("{} is not an http(s) origin", origin).into());
+    }
+    Ok(url.origin().ascii_serialization())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::backend::SessionBackend;
+    use actix_web::cookie::{Cookie, Key, SameSite};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    #[test]
+    fn test_is_allowed() {
+        let allowed = vec![parse_origin("https://Admin.Example.com:443/").unwrap()];
+        assert_eq!(allowed[0], "https://admin.example.com");
+
+        assert!(is_allowed(
+            "https://lynx.example.com",
+            "lynx.example.com",
+            &allowed
+        ));
+        assert!(is_allowed(
+            "http://localhost:8080",
+            "localhost:8080",
+            &allowed
+        ));
+        assert!(is_allowed(
+            "https://lynx.example.com/auth/login?next=/",
+            "lynx.example.com",
+            &allowed
+        ));
+        assert!(is_allowed(
+            "https://admin.example.com",
+            "lynx.example.com",
+            &allowed
+        ));
+
+        assert!(!is_allowed(
+            "https://evil.example.com",
+            "lynx.example.com",
+            &allowed
+        ));
+        assert!(!is_allowed(
+            "http://localhost:8081",
+            "localhost:8080",
+            &allowed
+        ));
+        assert!(!is_allowed(
+            "https://lynx.example.com:8443",
+            "lynx.example.com",
+            &allowed
+        ));
+        assert!(!is_allowed("null", "lynx.example.com", &allowed));
+        assert!(!is_allowed(
+            "file:///tmp/page.html",
+            "lynx.example.com",
+            &allowed
+        ));
+
+        assert!(parse_origin("example.com").is_err());
+        assert!(parse_origin("file:///tmp").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_middleware() {
+        let config = SessionConfig {
+            key: Key::generate(),
+            previous_keys: Vec::new(),
+            cookie_name: "session-cookie".to_string(),
+            secure: true,
+            same_site: SameSite::Strict,
+            lifetime: None,
+            backend: SessionBackend::Cookie,
+            allowed_origins: Vec::new(),
+        };
+        let app = init_service(
+            App::new()
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/", web::post().to(HttpResponse::Ok))
+                .wrap(CsrfProtection::new(&config)),
+        )
+        .await;
+        let request = |method: Method, origin: Option<&str>, cookie: bool| {
+            let mut req = TestRequest::default()
+                .method(method)
+                .insert_header((header::HOST, "lynx.example.com"));
+            if let Some(origin) = origin {
+                req = req.insert_header((header::ORIGIN, origin));
+            }
+            if cookie {
+                req = req.cookie(Cookie::new("session-cookie", "value"));
+            }
+            req.to_request()
+        };
+
+        let cases = [
+            (Method::POST, Some("https://evil.example.com"), true, 403),
+            (Method::POST, None, true, 403),
+            (Method::POST, Some("https://lynx.example.com"), true, 200),
+            (Method::POST, Some("https://evil.example.com"), false, 200),
+            (Method::GET, Some("https://evil.example.com"), true, 200),
+        ];
+        for (method, origin, cookie, status) in cases {
+            let res = call_service(&app, request(method, origin, cookie)).await;
+            assert_eq!(res.status(), status);
+        }
+    }
+}
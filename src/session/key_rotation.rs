@@ -0,0 +1,196 @@
+use crate::session::SessionConfig;
+
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::Error;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::rc::Rc;
+
+// Wrapped around the session middleware. A session cookie encrypted with
+// a previous key is re-encrypted with the current key before the session
+// middleware sees it, and the re-encrypted cookie is sent back to the
+// client, unless the response sets the session cookie itself anyway.
+
+pub struct KeyRotation {
+    config: Rc<SessionConfig>,
+}
+
+impl KeyRotation {
+    pub fn new(config: &SessionConfig) -> KeyRotation {
+        KeyRotation {
+            config: Rc::new(config.clone()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for KeyRotation
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = KeyRotationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(KeyRotationMiddleware {
+            service,
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct KeyRotationMiddleware<S> {
+    service: S,
+    config: Rc<SessionConfig>,
+}
+
+impl<S, B> Service<ServiceRequest> for KeyRotationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let migrated = if self.config.previous_keys.is_empty() {
+            None
+        } else {
+            migrate_cookie(&self.config, req.headers_mut())
+        };
+        let config = self.config.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(value) = migrated {
+                let already_set = res
+                    .response()
+                    .cookies()
+                    .any(|cookie| cookie.name() == config.cookie_name);
+                if !already_set {
+                    res.response_mut()
+                        .add_cookie(&response_cookie(&config, value))?;
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+// Returns the re-encrypted cookie value if the session cookie was encrypted
+// with a previous key, after rewriting the request's Cookie header with it
+fn migrate_cookie(config: &SessionConfig, headers: &mut HeaderMap) -> Option<String> {
+    let mut cookies: Vec<Cookie<'static>> = headers
+        .get_all(header::COOKIE)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| Cookie::parse_encoded(pair.trim().to_owned()).ok())
+        .collect();
+    let session_cookie = cookies
+        .iter_mut()
+        .find(|cookie| cookie.name() == config.cookie_name)?;
+
+    if decrypt(&config.key, session_cookie).is_some() {
+        return None;
+    }
+    let plain = config
+        .previous_keys
+        .iter()
+        .find_map(|key| decrypt(key, session_cookie))?;
+
+    let mut jar = CookieJar::new();
+    jar.private_mut(&config.key).add(plain);
+    let value = jar.get(&config.cookie_name)?.value().to_owned();
+    session_cookie.set_value(value.clone());
+
+    let header: Vec<String> = cookies
+        .iter()
+        .map(|cookie| cookie.encoded().to_string())
+        .collect();
+    headers.insert(
+        header::COOKIE,
+        HeaderValue::from_str(&header.join("; ")).ok()?,
+    );
+    Some(value)
+}
+
+fn decrypt(key: &Key, cookie: &Cookie<'static>) -> Option<Cookie<'static>> {
+    CookieJar::new().private(key).decrypt(cookie.clone())
+}
+
+fn response_cookie(config: &SessionConfig, value: String) -> Cookie<'static> {
+    let mut cookie = Cookie::build(config.cookie_name.clone(), value)
+        .path("/")
+        .secure(config.secure)
+        .http_only(true)
+        .same_site(config.same_site)
+        .finish();
+    if let Some(lifetime) = config.lifetime {
+        cookie.set_max_age(Duration::seconds(lifetime as i64));
+    }
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::backend::SessionBackend;
+    use actix_web::cookie::SameSite;
+
+    fn config(key: Key, previous_keys: Vec<Key>) -> SessionConfig {
+        SessionConfig {
+            key,
+            previous_keys,
+            cookie_name: "session-cookie".to_string(),
+            secure: true,
+            same_site: SameSite::Strict,
+            lifetime: None,
+            backend: SessionBackend::Cookie,
+            allowed_origins: Vec::new(),
+        }
+    }
+
+    fn encrypt(key: &Key, value: &str) -> String {
+        let mut jar = CookieJar::new();
+        jar.private_mut(key)
+            .add(Cookie::new("session-cookie", value.to_owned()));
+        jar.get("session-cookie").unwrap().encoded().to_string()
+    }
+
+    #[test]
+    fn test_migrate_previous_key() {
+        let (old_key, new_key) = (Key::generate(), Key::generate());
+        let config = config(new_key.clone(), vec![old_key.clone()]);
+        let mut headers = HeaderMap::new();
+        let header = "theme=dark; ".to_owned() + &encrypt(&old_key, "data");
+        headers.insert(header::COOKIE, HeaderValue::from_str(&header).unwrap());
+
+        let value = migrate_cookie(&config, &mut headers).unwrap();
+        let cookie = Cookie::new("session-cookie", value);
+        assert_eq!(decrypt(&new_key, &cookie).unwrap().value(), "data");
+        let header = headers.get(header::COOKIE).unwrap().to_str().unwrap();
+        assert!(header.starts_with("theme=dark; session-cookie="));
+        assert!(header.contains(cookie.encoded().to_string().as_str()));
+    }
+
+    #[test]
+    fn test_migrate_ignores_current_and_unknown_keys() {
+        let (old_key, new_key) = (Key::generate(), Key::generate());
+        let config = config(new_key.clone(), vec![old_key]);
+        for cookie in [encrypt(&new_key, "data"), encrypt(&Key::generate(), "data")] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+            assert_eq!(migrate_cookie(&config, &mut headers), None);
+            assert_eq!(headers.get(header::COOKIE).unwrap(), cookie.as_str());
+        }
+    }
+}
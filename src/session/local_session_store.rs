@@ -0,0 +1,179 @@
+use crate::auth_manager::token;
+use crate::session::{session_id, ServerSessionStore, SessionInfo, StoredSession};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// In-memory sessions for development, lost on restart and not shared
+// between balancer replicas. Expired sessions are dropped lazily.
+#[derive(Default)]
+pub struct LocalSessionStore {
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+impl LocalSessionStore {
+    pub fn new() -> LocalSessionStore {
+        LocalSessionStore::default()
+    }
+}
+
+fn remove_expired(sessions: &mut HashMap<String, StoredSession>) {
+    let now = token::now_secs();
+    sessions.retain(|_, session| session.expires_at > now);
+}
+
+#[async_trait]
+impl ServerSessionStore for LocalSessionStore {
+    async fn load(
+        &self,
+        session_key: &str,
+    ) -> Result<Option<HashMap<String, String>>, Box<dyn std::error::Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        remove_expired(&mut sessions);
+        Ok(sessions
+            .get(session_key)
+            .map(|session| session.state.clone()))
+    }
+
+    async fn create(
+        &self,
+        session_key: &str,
+        state: HashMap<String, String>,
+        ttl: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(session_key.to_owned(), StoredSession::new(state, ttl));
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        session_key: &str,
+        state: HashMap<String, String>,
+        ttl: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        remove_expired(&mut sessions);
+        match sessions.get_mut(session_key) {
+            Some(session) => {
+                *session = session.updated(state, ttl);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &str,
+        ttl: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(session_key) {
+            *session = session.updated(session.state.clone(), ttl);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.sessions.lock().unwrap().remove(session_key);
+        Ok(())
+    }
+
+    async fn list_sessions(
+        &self,
+        username: &str,
+    ) -> Result<Vec<SessionInfo>, Box<dyn std::error::Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        remove_expired(&mut sessions);
+        let mut infos: Vec<SessionInfo> = sessions
+            .iter()
+            .filter_map(|(key, session)| session.info(key))
+            .filter(|info| info.username == username)
+            .collect();
+        infos.sort_by_key(|info| info.created_at);
+        Ok(infos)
+    }
+
+    async fn terminate_session(
+        &self,
+        username: &str,
+        id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = sessions
+            .iter()
+            .find(|(key, session)| {
+                session.username.as_deref() == Some(username) && session_id(key) == id
+            })
+            .map(|(key, _)| key.clone());
+        match key {
+            Some(key) => {
+                sessions.remove(&key);
+                Ok(())
+            }
+            None => Err("Unknown session".into()),
+        }
+    }
+
+    async fn terminate_sessions(
+        &self,
+        username: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.username.as_deref() != Some(username));
+        Ok(before - sessions.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(username: &str) -> HashMap<String, String> {
+        let mut state = HashMap::new();
+        state.insert("session_username".to_string(), format!("\"{}\"", username));
+        state
+    }
+
+    #[actix_web::test]
+    async fn test_sessions() {
+        let store = LocalSessionStore::new();
+        store.create("a1", state("alice"), 60).await.unwrap();
+        store.create("a2", state("alice"), 60).await.unwrap();
+        store.create("b1", state("bob"), 60).await.unwrap();
+        store.create("anon", HashMap::new(), 60).await.unwrap();
+
+        assert_eq!(store.load("a1").await.unwrap(), Some(state("alice")));
+        assert_eq!(store.list_sessions("alice").await.unwrap().len(), 2);
+
+        // Bob cannot be terminated through Alice's sessions
+        let bob = store.list_sessions("bob").await.unwrap();
+        assert!(store.terminate_session("alice", &bob[0].id).await.is_err());
+        store.terminate_session("bob", &bob[0].id).await.unwrap();
+        assert_eq!(store.load("b1").await.unwrap(), None);
+        assert!(!store.update("b1", state("bob"), 60).await.unwrap());
+        assert_eq!(store.load("b1").await.unwrap(), None);
+
+        assert_eq!(store.terminate_sessions("alice").await.unwrap(), 2);
+        assert!(store.list_sessions("alice").await.unwrap().is_empty());
+        assert!(store.load("anon").await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_expired_sessions() {
+        let store = LocalSessionStore::new();
+        store.create("a1", state("alice"), 60).await.unwrap();
+        store
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut("a1")
+            .unwrap()
+            .expires_at = token::now_secs();
+        assert_eq!(store.load("a1").await.unwrap(), None);
+        assert!(store.list_sessions("alice").await.unwrap().is_empty());
+    }
+}
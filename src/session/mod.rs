@@ -0,0 +1,261 @@
+pub mod backend;
+pub mod csrf;
+pub mod key_rotation;
+pub mod local_session_store;
+pub mod redis_session_store;
+
+use crate::auth_manager::token;
+use crate::session::backend::SessionBackend;
+
+use actix_session::config::{BrowserSession, CookieContentSecurity, PersistentSession};
+use actix_session::SessionMiddleware;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Key, SameSite};
+use async_trait::async_trait;
+use jwt_simple::reexports::ct_codecs::{Base64, Decoder, Encoder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use tracing::info;
+
+// Session cookies are encrypted with `key`. Cookies encrypted with one of
+// `previous_keys` are still accepted and re-encrypted with `key`
+// (see `key_rotation`), so keys can be rotated without logging everyone out.
+// Keys are 64 random bytes, base64 encoded.
+
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub key: Key,
+    pub previous_keys: Vec<Key>,
+    pub cookie_name: String,
+    pub secure: bool,
+    pub same_site: SameSite,
+    // Seconds, `None` for cookies deleted when the browser is closed
+    pub lifetime: Option<u64>,
+    pub backend: SessionBackend,
+    // Normalized with `csrf::parse_origin`
+    pub allowed_origins: Vec<String>,
+}
+
+impl SessionConfig {
+    pub fn middleware(&self) -> SessionMiddleware<SessionBackend> {
+        let builder = SessionMiddleware::builder(self.backend.clone(), self.key.clone())
+            .cookie_name(self.cookie_name.clone())
+            .cookie_same_site(self.same_site)
+            .cookie_secure(self.secure)
+            .cookie_content_security(CookieContentSecurity::Private)
+            .cookie_http_only(true);
+        match self.lifetime {
+            Some(lifetime) => builder.session_lifecycle(
+                PersistentSession::default().session_ttl(Duration::seconds(lifetime as i64)),
+            ),
+            None => builder.session_lifecycle(BrowserSession::default()),
+        }
+        .build()
+    }
+}
+
+// Sessions kept on the server, the cookie only holds the session key.
+// Unlike cookie sessions they can be listed and terminated.
+#[async_trait]
+pub trait ServerSessionStore: Send + Sync {
+    async fn load(
+        &self,
+        session_key: &str,
+    ) -> Result<Option<HashMap<String, String>>, Box<dyn std::error::Error>>;
+
+    async fn create(
+        &self,
+        session_key: &str,
+        state: HashMap<String, String>,
+        ttl: u64,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Returns false if the session does not exist (anymore), e.g. because it was terminated
+    async fn update(
+        &self,
+        session_key: &str,
+        state: HashMap<String, String>,
+        ttl: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    async fn update_ttl(
+        &self,
+        session_key: &str,
+        ttl: u64,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn delete(&self, session_key: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn list_sessions(
+        &self,
+        username: &str,
+    ) -> Result<Vec<SessionInfo>, Box<dyn std::error::Error>>;
+
+    async fn terminate_session(
+        &self,
+        username: &str,
+        id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Returns the number of terminated sessions
+    async fn terminate_sessions(&self, username: &str)
+        -> Result<usize, Box<dyn std::error::Error>>;
+}
+
+// Session keys are bearer credentials, so sessions are only ever shown by their id
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    pub id: String,
+    pub username: String,
+    // Unix timestamps
+    pub created_at: u64,
+    pub last_active: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredSession {
+    pub state: HashMap<String, String>,
+    pub username: Option<String>,
+    pub created_at: u64,
+    pub last_active: u64,
+    pub expires_at: u64,
+}
+
+impl StoredSession {
+    pub fn new(state: HashMap<String, String>, ttl: u64) -> StoredSession {
+        let now = token::now_secs();
+        StoredSession {
+            username: session_username(&state),
+            state,
+            created_at: now,
+            last_active: now,
+            expires_at: now + ttl,
+        }
+    }
+
+    // Keeps `created_at` of the previous state
+    pub fn updated(&self, state: HashMap<String, String>, ttl: u64) -> StoredSession {
+        StoredSession {
+            created_at: self.created_at,
+            ..StoredSession::new(state, ttl)
+        }
+    }
+
+    pub fn info(&self, session_key: &str) -> Option<SessionInfo> {
+        Some(SessionInfo {
+            id: session_id(session_key),
+            username: self.username.clone()?,
+            created_at: self.created_at,
+            last_active: self.last_active,
+            expires_at: self.expires_at,
+        })
+    }
+}
+
+pub fn session_id(session_key: &str) -> String {
+    let hash = hmac_sha256::Hash::hash(session_key.as_bytes());
+    hash[0..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Values in the session state are JSON encoded
+fn session_username(state: &HashMap<String, String>) -> Option<String> {
+    serde_json::from_str(state.get("session_username")?).ok()
+}
+
+pub fn parse_key(encoded: &str) -> Result<Key, Box<dyn std::error::Error>> {
+    let bytes = match Base64::decode_to_vec(encoded.trim(), None) {
+        Ok(bytes) => bytes,
+        Err(_) => return Err("Session key is not valid base64".into()),
+    };
+    match Key::try_from(bytes.as_slice()) {
+        Ok(key) => Ok(key),
+        Err(_) => Err("Session key has to be at least 64 bytes".into()),
+    }
+}
+
+pub fn encode_key(key: &Key) -> String {
+    Base64::encode_to_string(key.master()).unwrap()
+}
+
+// Takes the key from `encoded` if set, e.g. from an environment variable,
+// otherwise from the file at `path`, which is created on first start
+pub fn load_key(encoded: Option<&str>, path: &str) -> Result<Key, Box<dyn std::error::Error>> {
+    if let Some(encoded) = encoded {
+        return parse_key(encoded);
+    }
+    if Path::new(path).exists() {
+        return parse_key(&fs::read_to_string(path)?);
+    }
+
+    info!("Session key file {} not found, generating a new key", path);
+    let key = Key::generate();
+    // Never readable by others, not even for a moment
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(encode_key(&key).as_bytes())?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_stored_session() {
+        let mut state = HashMap::new();
+        state.insert("session_username".to_string(), "\"alice\"".to_string());
+        let session = StoredSession::new(state.clone(), 60);
+        assert_eq!(session.username.as_deref(), Some("alice"));
+        assert_eq!(session.expires_at, session.created_at + 60);
+
+        let info = session.info("key").unwrap();
+        assert_eq!(info.id, session_id("key"));
+        assert_ne!(info.id, session_id("other key"));
+        assert_eq!(info.id.len(), 32);
+
+        state.remove("session_username");
+        let session = session.updated(state, 60);
+        assert_eq!(session.username, None);
+        assert_eq!(session.info("key"), None);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = Key::generate();
+        let parsed = parse_key(&encode_key(&key)).unwrap();
+        assert_eq!(parsed.master(), key.master());
+        assert!(parse_key("not base64!").is_err());
+        assert!(parse_key(&Base64::encode_to_string([0u8; 32]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_key_persists() {
+        let path = env::temp_dir()
+            .join(format!("lynx-session-{}.key", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_file(&path);
+
+        let generated = load_key(None, &path).unwrap();
+        let loaded = load_key(None, &path).unwrap();
+        assert_eq!(generated.master(), loaded.master());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let other = Key::generate();
+        let from_env = load_key(Some(&encode_key(&other)), &path).unwrap();
+        assert_eq!(from_env.master(), other.master());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
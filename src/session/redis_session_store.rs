@@ -0,0 +1,272 @@
+use crate::session::{session_id, ServerSessionStore, SessionInfo, StoredSession};
+
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use redis::aio::Connection;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+
+// Sessions are stored as JSON `StoredSession`, expiring with the session:
+// session:<session key> - {state, username, created_at, last_active, expires_at}
+// and listed per user, expired entries are removed when listing:
+// <username>_sessions - {<session key>, ...}
+pub struct RedisSessionStore {
+    con: Mutex<Connection>,
+}
+
+impl RedisSessionStore {
+    pub async fn new(url: String) -> RedisSessionStore {
+        let client = redis::Client::open(url).unwrap();
+        let con = client.get_async_connection().await.unwrap();
+
+        RedisSessionStore {
+            con: Mutex::new(con),
+        }
+    }
+}
+
+fn session_entry(session_key: &str) -> String {
+    "session:".to_owned() + session_key
+}
+
+fn user_sessions(username: &str) -> String {
+    username.to_owned() + "_sessions"
+}
+
+async fn get_session(
+    con: &mut Connection,
+    session_key: &str,
+) -> Result<Option<StoredSession>, Box<dyn std::error::Error>> {
+    let json: Option<String> = con.get(session_entry(session_key)).await?;
+    match json {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+async fn set_session(
+    con: &mut Connection,
+    session_key: &str,
+    session: &StoredSession,
+    ttl: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _: () = con
+        .set_ex(
+            session_entry(session_key),
+            serde_json::to_string(session)?,
+            ttl as usize,
+        )
+        .await?;
+    if let Some(username) = &session.username {
+        let _: () = con.sadd(user_sessions(username), session_key).await?;
+    }
+    Ok(())
+}
+
+async fn delete_session(
+    con: &mut Connection,
+    session_key: &str,
+    username: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _: () = con.del(session_entry(session_key)).await?;
+    let _: () = con.srem(user_sessions(username), session_key).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl ServerSessionStore for RedisSessionStore {
+    async fn load(
+        &self,
+        session_key: &str,
+    ) -> Result<Option<HashMap<String, String>>, Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        let session = get_session(&mut con, session_key).await?;
+        Ok(session.map(|session| session.state))
+    }
+
+    async fn create(
+        &self,
+        session_key: &str,
+        state: HashMap<String, String>,
+        ttl: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        set_session(&mut con, session_key, &StoredSession::new(state, ttl), ttl).await
+    }
+
+    async fn update(
+        &self,
+        session_key: &str,
+        state: HashMap<String, String>,
+        ttl: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        let previous = match get_session(&mut con, session_key).await? {
+            Some(previous) => previous,
+            None => return Ok(false),
+        };
+        let session = previous.updated(state, ttl);
+        if let Some(username) = &previous.username {
+            if previous.username != session.username {
+                let _: () = con.srem(user_sessions(username), session_key).await?;
+            }
+        }
+        set_session(&mut con, session_key, &session, ttl).await?;
+        Ok(true)
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &str,
+        ttl: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        let previous = get_session(&mut con, session_key).await?;
+        if let Some(previous) = previous {
+            let session = previous.updated(previous.state.clone(), ttl);
+            set_session(&mut con, session_key, &session, ttl).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        let session = get_session(&mut con, session_key).await?;
+        match session.and_then(|session| session.username) {
+            Some(username) => delete_session(&mut con, session_key, &username).await,
+            None => {
+                let _: () = con.del(session_entry(session_key)).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn list_sessions(
+        &self,
+        username: &str,
+    ) -> Result<Vec<SessionInfo>, Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        let keys: Vec<String> = con.smembers(user_sessions(username)).await?;
+        let mut infos = Vec::new();
+        for key in keys {
+            let session = get_session(&mut con, &key).await?;
+            match session.and_then(|session| session.info(&key)) {
+                Some(info) if info.username == username => infos.push(info),
+                _ => {
+                    let _: () = con.srem(user_sessions(username), &key).await?;
+                }
+            }
+        }
+        infos.sort_by_key(|info| info.created_at);
+        Ok(infos)
+    }
+
+    async fn terminate_session(
+        &self,
+        username: &str,
+        id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        let keys: Vec<String> = con.smembers(user_sessions(username)).await?;
+        match keys.iter().find(|key| session_id(key) == id) {
+            Some(key) => delete_session(&mut con, key, username).await,
+            None => Err("Unknown session".into()),
+        }
+    }
+
+    async fn terminate_sessions(
+        &self,
+        username: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut con = self.con.lock().await;
+        let keys: Vec<String> = con.smembers(user_sessions(username)).await?;
+        let mut terminated = 0;
+        for key in &keys {
+            let deleted: usize = con.del(session_entry(key)).await?;
+            terminated += deleted;
+        }
+        let _: () = con.del(user_sessions(username)).await?;
+        Ok(terminated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::env;
+
+    use super::*;
+
+    async fn get_store() -> RedisSessionStore {
+        let password = match env::var("REDIS_PASSWORD") {
+            Ok(v) => v,
+            Err(_) => panic!("$REDIS_PASSWORD is not set!"),
+        };
+
+        let url = "redis://default:".to_string() + &password + "@127.0.0.1:6379";
+        let store = RedisSessionStore::new(url).await;
+        store.terminate_sessions("lynx-test-alice").await.unwrap();
+        store.terminate_sessions("lynx-test-bob").await.unwrap();
+        store
+    }
+
+    fn state(username: &str) -> HashMap<String, String> {
+        let mut state = HashMap::new();
+        state.insert("session_username".to_string(), format!("\"{}\"", username));
+        state
+    }
+
+    #[tokio::test]
+    #[ignore]
+    #[serial]
+    async fn test_sessions() {
+        let store = get_store().await;
+        store
+            .create("lynx-test-a1", state("lynx-test-alice"), 60)
+            .await
+            .unwrap();
+        store
+            .create("lynx-test-a2", state("lynx-test-alice"), 60)
+            .await
+            .unwrap();
+        store
+            .create("lynx-test-b1", state("lynx-test-bob"), 60)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.load("lynx-test-a1").await.unwrap(),
+            Some(state("lynx-test-alice"))
+        );
+        assert_eq!(
+            store.list_sessions("lynx-test-alice").await.unwrap().len(),
+            2
+        );
+
+        // Bob cannot be terminated through Alice's sessions
+        let bob = store.list_sessions("lynx-test-bob").await.unwrap();
+        assert!(store
+            .terminate_session("lynx-test-alice", &bob[0].id)
+            .await
+            .is_err());
+        store
+            .terminate_session("lynx-test-bob", &bob[0].id)
+            .await
+            .unwrap();
+        assert_eq!(store.load("lynx-test-b1").await.unwrap(), None);
+        assert!(!store
+            .update("lynx-test-b1", state("lynx-test-bob"), 60)
+            .await
+            .unwrap());
+
+        assert_eq!(
+            store.terminate_sessions("lynx-test-alice").await.unwrap(),
+            2
+        );
+        assert!(store
+            .list_sessions("lynx-test-alice")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}